@@ -0,0 +1,215 @@
+use crate::local_time::system_timezone;
+
+use chrono_tz::Tz;
+use rocket_cors::{AllowedHeaders, AllowedOrigins, Cors, CorsOptions, Method};
+use rumqttc::MqttOptions;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Settings of the app besides Rocket's own, read from `Rocket.toml` or
+/// `ROCKET_` environment variables.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AppConfig {
+    /// Broker to exchange readings with, MQTT is disabled if not set
+    pub mqtt: Option<MqttConfig>,
+    /// InfluxDB to forward readings to, forwarding is disabled if not set
+    pub influx: Option<InfluxConfig>,
+    /// IANA timezone days start in, e.g. `Europe/Berlin`, the system's if not set
+    pub timezone: Option<Tz>,
+    /// Web pages allowed to call the API from a browser
+    #[serde(default)]
+    pub cors: CorsConfig,
+}
+
+impl AppConfig {
+    pub fn timezone(&self) -> Tz {
+        self.timezone.unwrap_or_else(system_timezone)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "MqttConfig::default_port")]
+    pub port: u16,
+    #[serde(default = "MqttConfig::default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "MqttConfig::default_keep_alive_seconds")]
+    pub keep_alive_seconds: u64,
+    /// Topic readings are published below, publishing is disabled if not set
+    pub publish_topic: Option<String>,
+    /// Topic Home Assistant expects discovery messages below
+    #[serde(default = "MqttConfig::default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Age of the last reading after which a sensor is reported unavailable
+    #[serde(default = "MqttConfig::default_stale_seconds")]
+    pub stale_seconds: u64,
+}
+
+impl MqttConfig {
+    pub fn default_port() -> u16 {
+        1883
+    }
+
+    pub fn default_client_id() -> String {
+        String::from("boiler-watch-api")
+    }
+
+    pub fn default_keep_alive_seconds() -> u64 {
+        30
+    }
+
+    pub fn default_discovery_prefix() -> String {
+        String::from("homeassistant")
+    }
+
+    pub fn default_stale_seconds() -> u64 {
+        300
+    }
+
+    /// Connection options of a client, `role` tells apart the clients of
+    /// this app as the broker drops connections with duplicate ids.
+    pub fn options(&self, role: &str) -> MqttOptions {
+        let mut options = MqttOptions::new(
+            format!("{}-{}", self.client_id, role),
+            &self.host,
+            self.port,
+        );
+        options.set_keep_alive(Duration::from_secs(self.keep_alive_seconds));
+
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.as_deref().unwrap_or_default());
+        }
+
+        options
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct InfluxConfig {
+    /// Write endpoint including database or org and bucket, e.g.
+    /// `http://influx:8086/api/v2/write?org=home&bucket=boiler`
+    pub url: String,
+    /// API token sent as `Authorization: Token <token>`
+    pub token: Option<String>,
+    #[serde(default = "InfluxConfig::default_measurement")]
+    pub measurement: String,
+    /// Tags added to every point, e.g. to tell houses apart
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default = "InfluxConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Batches kept while InfluxDB is unreachable, the oldest are dropped
+    /// beyond
+    #[serde(default = "InfluxConfig::default_max_queued_batches")]
+    pub max_queued_batches: usize,
+}
+
+impl InfluxConfig {
+    pub fn default_measurement() -> String {
+        String::from("temperature")
+    }
+
+    pub fn default_timeout_ms() -> u64 {
+        5000
+    }
+
+    /// A week of readings at the default interval of 15 seconds
+    pub fn default_max_queued_batches() -> usize {
+        40_320
+    }
+}
+
+/// Cross-origin requests browsers allow to the API. Preflights of other
+/// origins or methods are rejected.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CorsConfig {
+    /// Origins like `http://192.168.1.10:3000`, `*` allows every origin
+    #[serde(default = "CorsConfig::default_allowed_origins")]
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "CorsConfig::default_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    /// Whether browsers may send cookies and HTTP authentication along
+    #[serde(default)]
+    pub allow_credentials: bool,
+}
+
+#[derive(Debug)]
+pub enum CorsConfigError {
+    Method(String),
+    Cors(rocket_cors::Error),
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Self::default_allowed_origins(),
+            allowed_methods: Self::default_allowed_methods(),
+            allow_credentials: false,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Origin the Boiler Watch GUI is served from by default.
+    pub fn default_allowed_origins() -> Vec<String> {
+        vec![String::from("http://localhost:3000")]
+    }
+
+    pub fn default_allowed_methods() -> Vec<String> {
+        ["GET", "POST", "PUT", "DELETE"].map(String::from).to_vec()
+    }
+
+    pub fn to_cors(&self) -> Result<Cors, CorsConfigError> {
+        let allowed_origins = if self.allowed_origins.iter().any(|origin| origin == "*") {
+            AllowedOrigins::all()
+        } else {
+            AllowedOrigins::some_exact(&self.allowed_origins)
+        };
+
+        let allowed_methods = self
+            .allowed_methods
+            .iter()
+            .map(|method| {
+                Method::from_str(&method.to_uppercase())
+                    .map_err(|_| CorsConfigError::Method(method.to_owned()))
+            })
+            .collect::<Result<_, _>>()?;
+
+        CorsOptions {
+            allowed_origins,
+            allowed_methods,
+            allowed_headers: AllowedHeaders::some(&["Authorization", "Content-Type", "Accept"]),
+            allow_credentials: self.allow_credentials,
+            ..Default::default()
+        }
+        .to_cors()
+        .map_err(CorsConfigError::Cors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_cors_from_config() {
+        assert!(CorsConfig::default().to_cors().is_ok());
+
+        let config: CorsConfig = toml::from_str(
+            r#"
+            allowed_origins = ["*"]
+            allowed_methods = ["get", "FETCH ME"]
+        "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            config.to_cors(),
+            Err(CorsConfigError::Method(method)) if method == "FETCH ME"
+        ));
+    }
+}
@@ -0,0 +1,111 @@
+use crate::local_time::iso_date;
+
+use chrono_tz::Tz;
+use serde::Serialize;
+
+/// A change of the configuration or a destructive action requested over
+/// the API, kept as history of how the recorder came to be set up.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    date: u64,
+    /// `date` in the configured timezone, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_iso: Option<String>,
+    action: AuditAction,
+    /// Address of the peer of the connection, a reverse proxy if there is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<String>,
+    /// Name of the API token used, if tokens are required
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    /// The new configuration or what was removed
+    details: serde_json::Value,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    RecorderConfigSaved,
+    SensorCreated,
+    SensorUpdated,
+    SensorDeleted,
+    SensorsReloaded,
+    TokenCreated,
+    TokenRevoked,
+}
+
+impl AuditEntry {
+    pub fn new(
+        date: u64,
+        action: AuditAction,
+        client: Option<String>,
+        details: serde_json::Value,
+    ) -> Self {
+        Self {
+            date,
+            date_iso: None,
+            action,
+            client,
+            token: None,
+            details,
+        }
+    }
+
+    pub fn with_token(self, token: Option<String>) -> Self {
+        Self { token, ..self }
+    }
+
+    pub fn with_iso_date(self, timezone: &Tz) -> Self {
+        Self {
+            date_iso: Some(iso_date(self.date, timezone)),
+            ..self
+        }
+    }
+
+    pub fn date(&self) -> u64 {
+        self.date
+    }
+
+    pub fn action(&self) -> AuditAction {
+        self.action
+    }
+
+    pub fn client(&self) -> Option<&str> {
+        self.client.as_deref()
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn details(&self) -> &serde_json::Value {
+        &self.details
+    }
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::RecorderConfigSaved => "recorder_config_saved",
+            AuditAction::SensorCreated => "sensor_created",
+            AuditAction::SensorUpdated => "sensor_updated",
+            AuditAction::SensorDeleted => "sensor_deleted",
+            AuditAction::SensorsReloaded => "sensors_reloaded",
+            AuditAction::TokenCreated => "token_created",
+            AuditAction::TokenRevoked => "token_revoked",
+        }
+    }
+
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            "recorder_config_saved" => Some(AuditAction::RecorderConfigSaved),
+            "sensor_created" => Some(AuditAction::SensorCreated),
+            "sensor_updated" => Some(AuditAction::SensorUpdated),
+            "sensor_deleted" => Some(AuditAction::SensorDeleted),
+            "sensors_reloaded" => Some(AuditAction::SensorsReloaded),
+            "token_created" => Some(AuditAction::TokenCreated),
+            "token_revoked" => Some(AuditAction::TokenRevoked),
+            _ => None,
+        }
+    }
+}
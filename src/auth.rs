@@ -0,0 +1,405 @@
+use crate::audit::{AuditAction, AuditEntry};
+use crate::database::DatabaseAccessError;
+use crate::storage::Storage;
+use crate::temperature_recorder::now_millis;
+
+use rand::RngCore;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long stored tokens are used before they are loaded again, so tokens
+/// created or revoked on the command line take effect without a restart
+const TOKEN_CACHE_DURATION: Duration = Duration::from_secs(10);
+
+/// What a token may do: `read` only reads, `admin` also changes the
+/// configuration.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Read,
+    Admin,
+}
+
+/// An API token, of which only the SHA-256 hash is stored.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ApiToken {
+    name: String,
+    #[serde(skip)]
+    hash: String,
+    scope: Scope,
+    created: u64,
+}
+
+/// Checks the bearer tokens of requests against the stored ones.
+///
+/// As long as no token is stored, reading is allowed to everyone, so
+/// existing setups keep working until the first token is created. Changing
+/// the configuration always needs an `admin` token.
+pub struct Authenticator {
+    db: Arc<dyn Storage>,
+    cached: Mutex<Option<(Instant, Arc<Vec<ApiToken>>)>>,
+}
+
+/// Request guard allowing tokens of any scope.
+pub struct ReadAccess;
+
+/// Request guard allowing only `admin` tokens, naming the token used.
+pub struct AdminAccess {
+    token: String,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    UnknownToken,
+    InsufficientScope,
+    NoAdminToken,
+    NoAuthenticator,
+    Database(DatabaseAccessError),
+}
+
+#[derive(Debug)]
+pub enum TokenCommandError {
+    Usage,
+    DuplicateName(String),
+    UnknownToken(String),
+    Date(std::time::SystemTimeError),
+    Database(DatabaseAccessError),
+}
+
+pub const TOKEN_USAGE: &str = "Usage:
+    boiler-watch-api token create <name> [read|admin]
+    boiler-watch-api token revoke <name>
+    boiler-watch-api token list";
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(Scope::Read),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+
+    fn allows(&self, required: Scope) -> bool {
+        *self == Scope::Admin || required == Scope::Read
+    }
+}
+
+impl ApiToken {
+    pub fn new(name: String, hash: String, scope: Scope, created: u64) -> Self {
+        Self {
+            name,
+            hash,
+            scope,
+            created,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn scope(&self) -> Scope {
+        self.scope
+    }
+
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+}
+
+/// A new random token of 256 bits as hex string.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// Hash of a token as stored. Tokens are random, so an unsalted hash
+/// can't be looked up in precomputed tables.
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Authenticator {
+    pub fn new(db: Arc<dyn Storage>) -> Self {
+        Self {
+            db,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// The stored tokens, loaded at most every `TOKEN_CACHE_DURATION`.
+    fn tokens(&self) -> Result<Arc<Vec<ApiToken>>, AuthError> {
+        let mut cached = match self.cached.lock() {
+            Ok(cached) => cached,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Some((loaded, tokens)) = cached.as_ref() {
+            if loaded.elapsed() < TOKEN_CACHE_DURATION {
+                return Ok(tokens.clone());
+            }
+        }
+
+        let tokens = Arc::new(self.db.load_api_tokens().map_err(AuthError::Database)?);
+        *cached = Some((Instant::now(), tokens.clone()));
+        Ok(tokens)
+    }
+
+    /// The stored token matching `token` if it allows `required`, `Ok(None)`
+    /// if reading is allowed to everyone because no token is stored.
+    fn authenticate(
+        &self,
+        token: Option<&str>,
+        required: Scope,
+    ) -> Result<Option<ApiToken>, AuthError> {
+        let tokens = self.tokens()?;
+        if tokens.is_empty() {
+            return match required {
+                Scope::Read => Ok(None),
+                Scope::Admin => Err(AuthError::NoAdminToken),
+            };
+        }
+
+        let hash = hash_token(token.ok_or(AuthError::MissingToken)?);
+        let stored = tokens
+            .iter()
+            .find(|stored| stored.hash == hash)
+            .ok_or(AuthError::UnknownToken)?;
+
+        if stored.scope.allows(required) {
+            Ok(Some(stored.clone()))
+        } else {
+            Err(AuthError::InsufficientScope)
+        }
+    }
+}
+
+impl AdminAccess {
+    /// Name of the token used.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+fn authorize(request: &Request<'_>, required: Scope) -> Outcome<Option<ApiToken>, AuthError> {
+    let authenticator = match request.rocket().state::<Authenticator>() {
+        Some(authenticator) => authenticator,
+        None => return Outcome::Error((Status::InternalServerError, AuthError::NoAuthenticator)),
+    };
+
+    // the scheme is case-insensitive, see RFC 9110
+    let token = request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|header| header.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+        .map(|(_, token)| token.trim());
+
+    match authenticator.authenticate(token, required) {
+        Ok(stored) => Outcome::Success(stored),
+        Err(AuthError::InsufficientScope) => {
+            Outcome::Error((Status::Forbidden, AuthError::InsufficientScope))
+        }
+        Err(AuthError::NoAdminToken) => {
+            log::warn!(
+                "Refused to change the configuration without an admin token, create one with \
+                `boiler-watch-api token create <name> admin`"
+            );
+            Outcome::Error((Status::Unauthorized, AuthError::NoAdminToken))
+        }
+        Err(AuthError::Database(error)) => {
+            log::error!("Error loading API tokens: {:?}", error);
+            Outcome::Error((Status::InternalServerError, AuthError::Database(error)))
+        }
+        Err(error) => Outcome::Error((Status::Unauthorized, error)),
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ReadAccess {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(request, Scope::Read).map(|_| ReadAccess)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminAccess {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(request, Scope::Admin).and_then(|token| match token {
+            Some(token) => Outcome::Success(AdminAccess { token: token.name }),
+            None => Outcome::Error((Status::Unauthorized, AuthError::NoAdminToken)),
+        })
+    }
+}
+
+/// Runs `token create|revoke|list` given on the command line.
+pub fn run_token_command(args: &[String], db: &dyn Storage) -> Result<(), TokenCommandError> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["create", name] | ["create", name, _] => {
+            let scope = match args.get(2) {
+                Some(scope) => Scope::parse(scope).ok_or(TokenCommandError::Usage)?,
+                None => Scope::Read,
+            };
+
+            let tokens = db.load_api_tokens().map_err(TokenCommandError::Database)?;
+            if tokens.iter().any(|token| token.name == *name) {
+                return Err(TokenCommandError::DuplicateName(name.to_string()));
+            }
+
+            let token = generate_token();
+            let created = now_millis().map_err(TokenCommandError::Date)?;
+            let api_token = ApiToken::new(name.to_string(), hash_token(&token), scope, created);
+            db.save_api_token(&api_token)
+                .map_err(TokenCommandError::Database)?;
+            audit(db, created, AuditAction::TokenCreated, &api_token);
+
+            println!(
+                "Created {} token {}, it is not shown again:",
+                scope.as_str(),
+                name
+            );
+            println!("{}", token);
+            Ok(())
+        }
+        ["revoke", name] => match db.delete_api_token(name) {
+            Ok(0) => Err(TokenCommandError::UnknownToken(name.to_string())),
+            Ok(_) => {
+                let revoked = now_millis().map_err(TokenCommandError::Date)?;
+                audit(
+                    db,
+                    revoked,
+                    AuditAction::TokenRevoked,
+                    &serde_json::json!({ "name": name }),
+                );
+                println!("Revoked token {}", name);
+                Ok(())
+            }
+            Err(error) => Err(TokenCommandError::Database(error)),
+        },
+        ["list"] => {
+            for token in db.load_api_tokens().map_err(TokenCommandError::Database)? {
+                println!(
+                    "{}\t{}\t{}",
+                    token.name,
+                    token.scope.as_str(),
+                    token.created
+                );
+            }
+            Ok(())
+        }
+        _ => Err(TokenCommandError::Usage),
+    }
+}
+
+/// Records a change of the tokens made on the command line, which has no
+/// client.
+fn audit<T: Serialize>(db: &dyn Storage, date: u64, action: AuditAction, details: &T) {
+    let details = serde_json::to_value(details).unwrap_or(serde_json::Value::Null);
+
+    if let Err(error) = db.save_audit_entry(&AuditEntry::new(date, action, None, details)) {
+        eprintln!("Error saving audit entry: {:?}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_storage::MemoryStorage;
+
+    #[test]
+    fn authenticates_stored_tokens_once_one_exists() {
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let authenticator = Authenticator::new(db.clone());
+
+        assert!(matches!(
+            authenticator.authenticate(None, Scope::Read),
+            Ok(None)
+        ));
+        assert!(matches!(
+            authenticator.authenticate(None, Scope::Admin),
+            Err(AuthError::NoAdminToken)
+        ));
+
+        let token = generate_token();
+        db.save_api_token(&ApiToken::new(
+            String::from("gui"),
+            hash_token(&token),
+            Scope::Read,
+            0,
+        ))
+        .unwrap();
+
+        // the new token takes effect once the cached ones expired
+        assert!(matches!(
+            authenticator.authenticate(None, Scope::Read),
+            Ok(None)
+        ));
+        let authenticator = Authenticator::new(db.clone());
+
+        let stored = authenticator
+            .authenticate(Some(&token), Scope::Read)
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.name(), "gui");
+        assert!(matches!(
+            authenticator.authenticate(Some(&token), Scope::Admin),
+            Err(AuthError::InsufficientScope)
+        ));
+        assert!(matches!(
+            authenticator.authenticate(None, Scope::Read),
+            Err(AuthError::MissingToken)
+        ));
+        assert!(matches!(
+            authenticator.authenticate(Some("guessed"), Scope::Read),
+            Err(AuthError::UnknownToken)
+        ));
+    }
+
+    #[test]
+    fn audits_tokens_created_and_revoked_on_the_command_line() {
+        let db = MemoryStorage::new();
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+        run_token_command(&args(&["create", "gui", "admin"]), &db).unwrap();
+        run_token_command(&args(&["revoke", "gui"]), &db).unwrap();
+
+        let entries = db.load_audit_entries(0, u64::MAX).unwrap();
+        let actions: Vec<AuditAction> = entries.iter().map(AuditEntry::action).collect();
+        assert_eq!(
+            actions,
+            vec![AuditAction::TokenCreated, AuditAction::TokenRevoked]
+        );
+        assert!(entries.iter().all(|entry| entry.client().is_none()));
+        assert_eq!(entries[0].details()["name"], "gui");
+        assert_eq!(entries[0].details()["scope"], "admin");
+        assert!(entries[0].details().get("hash").is_none());
+    }
+}
@@ -0,0 +1,429 @@
+use crate::daily_stats::local_days;
+use crate::database::DatabaseAccessError;
+use crate::local_time::iso_date;
+use crate::recorder_scheduler::TemperatureSink;
+use crate::sensor_config::SensorConfig;
+use crate::storage::Storage;
+use crate::temperature_recorder::{now_millis, TemperaturesByTime, MILLIS_PER_DAY};
+
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Readings the rate of the flow temperature is computed over, so a single
+/// noisy reading neither starts nor ends a cycle
+const RATE_SAMPLES: usize = 3;
+
+/// How far back readings are replayed on start to find a running cycle
+const RESTORE_MILLIS: u64 = MILLIS_PER_DAY;
+
+/// How burner on/off periods are detected, either from a status sensor or
+/// from the rise and fall of the flow temperature.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BurnerConfig {
+    /// Sensor reporting the burner state, e.g. a Modbus status register
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_sensor: Option<String>,
+    /// Status values at or above this mean the burner is on
+    #[serde(default = "BurnerConfig::default_on_threshold")]
+    on_threshold: f32,
+    /// Flow sensor to detect the burner state from if there is no status sensor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flow_sensor: Option<String>,
+    /// Rise of the flow temperature in degrees per minute starting a cycle
+    #[serde(default = "BurnerConfig::default_rise_per_minute")]
+    rise_per_minute: f32,
+    /// Fall of the flow temperature in degrees per minute ending a cycle
+    #[serde(default = "BurnerConfig::default_fall_per_minute")]
+    fall_per_minute: f32,
+}
+
+/// A period the burner was on, in epoch milliseconds.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BurnerCycle {
+    start: u64,
+    end: u64,
+    /// `start` and `end` in the configured timezone, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_iso: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_iso: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CycleStats {
+    count: usize,
+    on_seconds: u64,
+    /// Share of the requested range the burner was on
+    on_ratio: f32,
+    min_on_seconds: Option<u64>,
+    max_on_seconds: Option<u64>,
+    mean_on_seconds: Option<u64>,
+    /// Mean pause between the end of a cycle and the start of the next one
+    mean_off_seconds: Option<u64>,
+    days: Vec<DailyCycleStats>,
+    cycles: Vec<BurnerCycle>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DailyCycleStats {
+    /// Calendar day as `YYYY-MM-DD`
+    day: String,
+    /// Cycles started on this day
+    count: usize,
+    on_seconds: u64,
+    on_ratio: f32,
+}
+
+/// Detects burner cycles from every tick and stores the finished ones.
+///
+/// A cycle running while the app restarts is found again by replaying the
+/// stored readings since the last stored cycle on the first tick.
+pub struct BurnerCycleDetector {
+    db: Arc<dyn Storage>,
+    recent: VecDeque<(u64, f32)>,
+    started: Option<u64>,
+    restored: bool,
+}
+
+impl BurnerConfig {
+    pub fn default_on_threshold() -> f32 {
+        0.5
+    }
+
+    pub fn default_rise_per_minute() -> f32 {
+        1.0
+    }
+
+    pub fn default_fall_per_minute() -> f32 {
+        0.5
+    }
+
+    /// The sensor the burner state is detected from.
+    pub fn sensor(&self) -> Option<&str> {
+        self.status_sensor
+            .as_deref()
+            .or(self.flow_sensor.as_deref())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.status_sensor.is_some() == self.flow_sensor.is_some() {
+            return Err(String::from(
+                "Burner needs either a status sensor or a flow sensor",
+            ));
+        }
+
+        if self.rise_per_minute <= 0.0 || self.fall_per_minute < 0.0 {
+            return Err(String::from(
+                "Burner rise per minute must be positive and fall per minute not negative",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl BurnerCycle {
+    pub fn new(start: u64, end: u64) -> Self {
+        Self {
+            start,
+            end,
+            start_iso: None,
+            end_iso: None,
+        }
+    }
+
+    pub fn with_iso_dates(self, timezone: &Tz) -> Self {
+        Self {
+            start_iso: Some(iso_date(self.start, timezone)),
+            end_iso: Some(iso_date(self.end, timezone)),
+            ..self
+        }
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Milliseconds of the cycle within `from` and `to`.
+    fn millis_within(&self, from: u64, to: u64) -> u64 {
+        self.end.min(to).saturating_sub(self.start.max(from))
+    }
+}
+
+impl BurnerCycleDetector {
+    pub fn new(db: Arc<dyn Storage>) -> Self {
+        Self {
+            db,
+            recent: VecDeque::new(),
+            started: None,
+            restored: false,
+        }
+    }
+
+    /// Replays the readings since the end of the last stored cycle, at most
+    /// a day back, and saves the cycles finished meanwhile.
+    fn restore(&mut self, config: &BurnerConfig) -> Result<(), DatabaseAccessError> {
+        let now = now_millis().map_err(DatabaseAccessError::Date)?;
+        let since = now.saturating_sub(RESTORE_MILLIS);
+        let since = match self.db.load_burner_cycles(since, now)?.last() {
+            Some(cycle) => cycle.end.max(since),
+            None => since,
+        };
+
+        for temperatures in self.db.load_temperatures_since(since)? {
+            if let Some(cycle) = self.update(config, &temperatures) {
+                self.save(&cycle);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn save(&self, cycle: &BurnerCycle) {
+        log::debug!("Burner cycle finished {:?}", cycle);
+
+        if let Err(error) = self.db.save_burner_cycle(cycle) {
+            log::error!("Error saving burner cycle {:?}", error);
+        }
+    }
+
+    /// Feeds the readings of one tick and returns the cycle they finished, if any.
+    fn update(
+        &mut self,
+        config: &BurnerConfig,
+        temperatures: &TemperaturesByTime,
+    ) -> Option<BurnerCycle> {
+        let sensor = config.sensor()?;
+        let value = temperatures
+            .temperatures()
+            .iter()
+            .find(|temperature| temperature.name() == sensor)?
+            .value();
+        let date = temperatures.date();
+
+        // a status sensor tells the state at this reading, the flow
+        // temperature only between this and the last reading
+        let (on, changed_at) = if config.status_sensor.is_some() {
+            (value >= config.on_threshold, date)
+        } else {
+            if self.recent.back().is_some_and(|last| last.0 >= date) {
+                return None;
+            }
+            self.recent.push_back((date, value));
+            if self.recent.len() > RATE_SAMPLES {
+                self.recent.pop_front();
+            }
+
+            let (first_date, first_value) = *self.recent.front()?;
+            let (last_date, _) = *self.recent.iter().nth_back(1)?;
+            let minutes = (date - first_date) as f32 / 60_000.0;
+            let rate = (value - first_value) / minutes;
+            let on = match self.started {
+                Some(_) => rate > -config.fall_per_minute,
+                None => rate >= config.rise_per_minute,
+            };
+            (on, last_date)
+        };
+
+        match (self.started, on) {
+            (None, true) => {
+                self.started = Some(changed_at);
+                None
+            }
+            (Some(start), false) => {
+                self.started = None;
+                Some(BurnerCycle::new(start, changed_at))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl TemperatureSink for BurnerCycleDetector {
+    fn send(&mut self, sensor_config: &SensorConfig, temperatures: &TemperaturesByTime) {
+        let config = match sensor_config.burner() {
+            Some(config) => config,
+            None => return,
+        };
+
+        if !self.restored {
+            self.restored = true;
+            if let Err(error) = self.restore(config) {
+                log::error!("Error restoring burner cycle {:?}", error);
+            }
+        }
+
+        if let Some(cycle) = self.update(config, temperatures) {
+            self.save(&cycle);
+        }
+    }
+}
+
+impl CycleStats {
+    /// Statistics of the cycles started between `from` and `to`, per local
+    /// calendar day in `timezone`.
+    pub fn new(cycles: Vec<BurnerCycle>, from: u64, to: u64, timezone: &Tz) -> Self {
+        let durations: Vec<u64> = cycles
+            .iter()
+            .map(|cycle| (cycle.end - cycle.start) / 1000)
+            .collect();
+        let pauses: Vec<u64> = cycles
+            .windows(2)
+            .map(|pair| pair[1].start.saturating_sub(pair[0].end) / 1000)
+            .collect();
+        let on_millis: u64 = cycles.iter().map(|c| c.millis_within(from, to)).sum();
+
+        // cycles running past midnight count for both days
+        let days = local_days(from, to, timezone)
+            .into_iter()
+            .map(|day| {
+                let on_millis: u64 = cycles
+                    .iter()
+                    .map(|cycle| cycle.millis_within(day.start, day.end))
+                    .sum();
+                DailyCycleStats {
+                    day: day.date.format("%Y-%m-%d").to_string(),
+                    count: cycles
+                        .iter()
+                        .filter(|cycle| cycle.start >= day.start && cycle.start < day.end)
+                        .count(),
+                    on_seconds: on_millis / 1000,
+                    on_ratio: ratio(on_millis, day.end - day.start),
+                }
+            })
+            .collect();
+
+        Self {
+            count: cycles.len(),
+            on_seconds: on_millis / 1000,
+            on_ratio: ratio(on_millis, to.saturating_sub(from)),
+            min_on_seconds: durations.iter().min().copied(),
+            max_on_seconds: durations.iter().max().copied(),
+            mean_on_seconds: mean(&durations),
+            mean_off_seconds: mean(&pauses),
+            days,
+            cycles,
+        }
+    }
+
+    pub fn with_iso_dates(self, timezone: &Tz) -> Self {
+        Self {
+            cycles: self
+                .cycles
+                .into_iter()
+                .map(|cycle| cycle.with_iso_dates(timezone))
+                .collect(),
+            ..self
+        }
+    }
+}
+
+fn ratio(part: u64, whole: u64) -> f32 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f32 / whole as f32
+    }
+}
+
+fn mean(values: &[u64]) -> Option<u64> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<u64>() / values.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_storage::MemoryStorage;
+    use crate::temperature_recorder::{Temperature, MILLIS_PER_DAY};
+
+    fn flow(date: u64, value: f32) -> TemperaturesByTime {
+        TemperaturesByTime::new(date, vec![Temperature::new(String::from("flow"), value)])
+    }
+
+    #[test]
+    fn detects_cycles_from_flow_temperature() {
+        let config: BurnerConfig = toml::from_str(r#"flow_sensor = "flow""#).unwrap();
+        let mut detector = BurnerCycleDetector::new(Arc::new(MemoryStorage::new()));
+        let minute = 60_000;
+
+        // the dip at minute 4 is noise, not the end of the cycle
+        let cycles: Vec<BurnerCycle> = [40.0, 40.2, 43.0, 46.0, 45.4, 47.5, 47.0, 44.0, 41.0]
+            .iter()
+            .enumerate()
+            .filter_map(|(i, value)| detector.update(&config, &flow(i as u64 * minute, *value)))
+            .collect();
+
+        assert_eq!(cycles, vec![BurnerCycle::new(minute, 6 * minute)]);
+        assert_eq!(detector.started, None);
+    }
+
+    #[test]
+    fn restores_running_cycle_from_stored_readings() {
+        let config = SensorConfig::parse(
+            r#"
+            [burner]
+            flow_sensor = "flow"
+
+            [[sensors]]
+            name = "flow"
+            kind = "mqtt"
+            topic = "boiler/flow"
+        "#,
+        )
+        .unwrap();
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let minute = 60_000;
+        let now = now_millis().unwrap();
+        let start = now - 10 * minute;
+
+        for (i, value) in [40.0, 40.2, 43.0, 46.0, 49.0].iter().enumerate() {
+            db.save_temperatures(flow(start + i as u64 * minute, *value))
+                .unwrap();
+        }
+
+        let mut detector = BurnerCycleDetector::new(db.clone());
+        detector.send(&config, &flow(start + 5 * minute, 52.0));
+        assert_eq!(detector.started, Some(start + minute));
+
+        for (i, value) in [50.0, 47.0].iter().enumerate() {
+            detector.send(&config, &flow(start + (6 + i as u64) * minute, *value));
+        }
+        assert_eq!(
+            db.load_burner_cycles(start, now).unwrap(),
+            vec![BurnerCycle::new(start + minute, start + 6 * minute)]
+        );
+    }
+
+    #[test]
+    fn computes_statistics_per_day() {
+        let hour = 3_600_000;
+        let cycles = vec![
+            BurnerCycle::new(hour, 2 * hour),
+            BurnerCycle::new(5 * hour, 6 * hour),
+            BurnerCycle::new(23 * hour, 25 * hour),
+        ];
+
+        let stats = CycleStats::new(cycles, 0, 2 * MILLIS_PER_DAY, &Tz::UTC);
+
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.on_seconds, 4 * 3600);
+        assert_eq!(stats.mean_on_seconds, Some(4800));
+        assert_eq!(stats.mean_off_seconds, Some(10 * 3600));
+        assert_eq!(stats.days.len(), 2);
+        assert_eq!(stats.days[0].day, "1970-01-01");
+        assert_eq!(stats.days[0].count, 3);
+        assert_eq!(stats.days[0].on_seconds, 3 * 3600);
+        assert_eq!(stats.days[1].count, 0);
+        assert_eq!(stats.days[1].on_seconds, 3600);
+    }
+}
@@ -0,0 +1,146 @@
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Debug)]
+pub enum CommandError {
+    Spawn(io::Error),
+    Wait(io::Error),
+    /// The command did not finish in time and was killed
+    Timeout(Duration),
+    /// The command exited unsuccessfully
+    Failed(ExitStatus, String),
+}
+
+/// Runs `command` and returns its stdout, killing it if it does not finish
+/// within `timeout`.
+pub fn run(command: &[String], timeout: Duration) -> Result<String, CommandError> {
+    let (program, arguments) = command.split_first().ok_or_else(|| {
+        CommandError::Spawn(io::Error::new(io::ErrorKind::InvalidInput, "Empty command"))
+    })?;
+
+    let mut child = Command::new(program)
+        .args(arguments)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // a group of its own, so a timeout also kills what the command started
+        .process_group(0)
+        .spawn()
+        .map_err(CommandError::Spawn)?;
+
+    // Drain both pipes while waiting, so a chatty command can't block on a full pipe
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let status = wait_with_timeout(&mut child, timeout);
+
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    let status = status?;
+
+    if !status.success() {
+        return Err(CommandError::Failed(status, stderr));
+    }
+
+    if !stderr.trim().is_empty() {
+        log::warn!("Command {:?} wrote to stderr: {}", command, stderr.trim());
+    }
+
+    Ok(stdout)
+}
+
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = String::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_string(&mut output);
+        }
+        output
+    })
+}
+
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> Result<ExitStatus, CommandError> {
+    let start = Instant::now();
+
+    loop {
+        if let Some(status) = child.try_wait().map_err(CommandError::Wait)? {
+            return Ok(status);
+        }
+
+        if start.elapsed() >= timeout {
+            kill_process_group(child);
+            let _ = child.wait();
+            return Err(CommandError::Timeout(timeout));
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Kills the command and every process it started which still holds its
+/// pipes, e.g. the parts of a shell pipeline.
+fn kill_process_group(child: &mut Child) {
+    // The child was not waited for yet, so its id still names its group
+    // SAFETY: kill only sends a signal and takes no pointers
+    let result = unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+
+    if result != 0 {
+        log::warn!(
+            "Error killing process group of command: {}",
+            io::Error::last_os_error()
+        );
+        let _ = child.kill();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell(script: &str) -> Vec<String> {
+        vec![String::from("sh"), String::from("-c"), String::from(script)]
+    }
+
+    #[test]
+    fn returns_stdout_of_successful_command() {
+        let output = run(&shell("echo 21.5"), Duration::from_secs(5)).unwrap();
+        assert_eq!(output.trim(), "21.5");
+    }
+
+    #[test]
+    fn captures_stderr_and_status_of_failed_command() {
+        let error = run(&shell("echo broken >&2; exit 3"), Duration::from_secs(5)).unwrap_err();
+
+        match error {
+            CommandError::Failed(status, stderr) => {
+                assert_eq!(status.code(), Some(3));
+                assert_eq!(stderr.trim(), "broken");
+            }
+            error => panic!("Unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn kills_hanging_command() {
+        let start = Instant::now();
+        let error = run(&shell("exec sleep 10"), Duration::from_millis(200)).unwrap_err();
+
+        assert!(matches!(error, CommandError::Timeout(_)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn kills_every_process_of_hanging_pipeline() {
+        let start = Instant::now();
+        // the pipes stay open, and run waits for them, until sleep is killed too
+        let error = run(&shell("sleep 10 | cat"), Duration::from_millis(200)).unwrap_err();
+
+        assert!(matches!(error, CommandError::Timeout(_)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,282 @@
+use crate::database::DatabaseAccessError;
+use crate::local_time::iso_date;
+use crate::sensor_config::SensorConfig;
+use crate::storage::{interval_at, IntervalChange, Storage};
+use crate::temperature_recorder::{now_millis, Resolution, Temperature, TemperaturesByTime};
+
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A period without readings of a sensor, from when the next reading was
+/// due until the next one was recorded, in epoch milliseconds.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Gap {
+    start: u64,
+    end: u64,
+    /// `start` and `end` in the configured timezone, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_iso: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_iso: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SensorCoverage {
+    sensor: String,
+    /// Share of the requested range not within a gap
+    coverage_percent: f32,
+    gaps: Vec<Gap>,
+}
+
+/// Time between readings at some point in time: the recorder interval
+/// used back then for raw temperatures or the bucket size of rollups.
+pub struct ExpectedInterval {
+    resolution: Resolution,
+    history: Vec<IntervalChange>,
+}
+
+impl ExpectedInterval {
+    /// Expected intervals of the temperatures loaded since `since`.
+    pub fn load(db: &dyn Storage, since: u64) -> Result<Self, DatabaseAccessError> {
+        let config = db.load_recorder_config()?;
+        let now = now_millis().map_err(DatabaseAccessError::Date)?;
+
+        Ok(Self {
+            resolution: config.resolution_since(since, now),
+            history: db.load_interval_history()?,
+        })
+    }
+
+    fn millis_at(&self, date: u64) -> u64 {
+        match self.resolution {
+            Resolution::Raw => interval_at(&self.history, date)
+                .map(|seconds| seconds as u64 * 1000)
+                .unwrap_or(1000),
+            rollup => rollup.bucket_millis(),
+        }
+    }
+}
+
+impl Gap {
+    pub fn new(start: u64, end: u64) -> Self {
+        Self {
+            start,
+            end,
+            start_iso: None,
+            end_iso: None,
+        }
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn with_iso_dates(self, timezone: &Tz) -> Self {
+        Self {
+            start_iso: Some(iso_date(self.start, timezone)),
+            end_iso: Some(iso_date(self.end, timezone)),
+            ..self
+        }
+    }
+}
+
+impl SensorCoverage {
+    pub fn with_iso_dates(self, timezone: &Tz) -> Self {
+        Self {
+            gaps: self
+                .gaps
+                .into_iter()
+                .map(|gap| gap.with_iso_dates(timezone))
+                .collect(),
+            ..self
+        }
+    }
+}
+
+/// Whether readings `millis` apart are too far apart for the expected
+/// interval, allowing for some delay of the scheduler.
+fn is_gap(millis: u64, expected_millis: u64) -> bool {
+    millis * 2 > expected_millis * 3
+}
+
+/// Gaps between `from` and `to` in the ascending reading dates of a sensor.
+pub fn find_gaps(dates: &[u64], from: u64, to: u64, expected: &ExpectedInterval) -> Vec<Gap> {
+    let mut gaps = vec![];
+    let mut last: Option<u64> = None;
+
+    for &date in dates.iter().filter(|date| **date < to) {
+        match last {
+            None if is_gap(date.saturating_sub(from), expected.millis_at(date)) => {
+                gaps.push(Gap::new(from, date));
+            }
+            Some(last) => {
+                // readings right after a change of the interval follow the new one
+                let interval = expected.millis_at(last).max(expected.millis_at(date));
+                let start = (last + expected.millis_at(last)).max(from);
+                if is_gap(date - last, interval) && start < date {
+                    gaps.push(Gap::new(start, date));
+                }
+            }
+            None => {}
+        }
+
+        last = Some(date);
+    }
+
+    match last {
+        None if to > from => gaps.push(Gap::new(from, to)),
+        Some(last) if is_gap(to.saturating_sub(last), expected.millis_at(last)) => {
+            gaps.push(Gap::new((last + expected.millis_at(last)).max(from), to))
+        }
+        _ => {}
+    }
+
+    gaps
+}
+
+fn dates_by_sensor(temperatures: &[TemperaturesByTime]) -> BTreeMap<String, Vec<u64>> {
+    let mut dates: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+
+    for temperatures_by_time in temperatures {
+        for temperature in temperatures_by_time.temperatures() {
+            dates
+                .entry(temperature.name())
+                .or_default()
+                .push(temperatures_by_time.date());
+        }
+    }
+
+    dates
+}
+
+/// Gaps and coverage of each configured or recorded sensor, or only of
+/// `sensor`, between `from` and `to`.
+pub fn coverage(
+    db: &dyn Storage,
+    sensor_config: &SensorConfig,
+    from: u64,
+    to: u64,
+    sensor: Option<&str>,
+) -> Result<Vec<SensorCoverage>, DatabaseAccessError> {
+    let expected = ExpectedInterval::load(db, from)?;
+    let mut dates = dates_by_sensor(&db.load_temperatures_between(from, to)?);
+
+    // sensors without a single reading are not covered at all
+    for configured in sensor_config.sensors() {
+        dates.entry(configured.name().to_owned()).or_default();
+    }
+
+    let range = to.saturating_sub(from);
+
+    Ok(dates
+        .into_iter()
+        .filter(|(name, _)| sensor.is_none_or(|sensor| sensor == name))
+        .map(|(name, dates)| {
+            let gaps = find_gaps(&dates, from, to, &expected);
+            let missing: u64 = gaps.iter().map(|gap| gap.end - gap.start).sum();
+            let coverage_percent = if range == 0 {
+                100.0
+            } else {
+                (range.saturating_sub(missing) as f32 / range as f32 * 100.0).clamp(0.0, 100.0)
+            };
+
+            SensorCoverage {
+                sensor: name,
+                coverage_percent,
+                gaps,
+            }
+        })
+        .collect())
+}
+
+/// Inserts a missing reading at the start of every gap of a sensor, so
+/// charts don't draw a line across it.
+pub fn with_gap_markers(
+    temperatures: Vec<TemperaturesByTime>,
+    from: u64,
+    to: u64,
+    expected: &ExpectedInterval,
+) -> Vec<TemperaturesByTime> {
+    let dates = dates_by_sensor(&temperatures);
+    let mut by_date: BTreeMap<u64, Vec<Temperature>> = temperatures
+        .into_iter()
+        .map(|temperatures| (temperatures.date(), temperatures.temperatures()))
+        .collect();
+
+    for (name, dates) in dates {
+        for gap in find_gaps(&dates, from, to, expected) {
+            by_date
+                .entry(gap.start())
+                .or_default()
+                .push(Temperature::missing(name.clone()));
+        }
+    }
+
+    by_date
+        .into_iter()
+        .map(|(date, temperatures)| TemperaturesByTime::new(date, temperatures))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1000;
+
+    fn expected() -> ExpectedInterval {
+        ExpectedInterval {
+            resolution: Resolution::Raw,
+            history: vec![
+                IntervalChange {
+                    since: 0,
+                    interval_seconds: 15,
+                },
+                IntervalChange {
+                    since: 100 * SECOND,
+                    interval_seconds: 60,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn finds_gaps_with_historical_interval() {
+        let dates = [30, 45, 61, 120, 180, 240, 420].map(|date| date * SECOND);
+
+        let gaps = find_gaps(&dates, 0, 600 * SECOND, &expected());
+
+        assert_eq!(
+            gaps,
+            vec![
+                Gap::new(0, 30 * SECOND),
+                // 61 to 120 is the change to the interval of a minute
+                Gap::new(300 * SECOND, 420 * SECOND),
+                Gap::new(480 * SECOND, 600 * SECOND),
+            ]
+        );
+        assert_eq!(
+            find_gaps(&[], 0, 10 * SECOND, &expected()),
+            vec![Gap::new(0, 10 * SECOND)]
+        );
+    }
+
+    #[test]
+    fn inserts_markers_at_gaps() {
+        let reading = |date: u64| {
+            TemperaturesByTime::new(date, vec![Temperature::new(String::from("flow"), 40.0)])
+        };
+        let temperatures = vec![reading(0), reading(15 * SECOND), reading(75 * SECOND)];
+
+        let marked = with_gap_markers(temperatures, 0, 80 * SECOND, &expected());
+
+        let dates: Vec<u64> = marked.iter().map(|t| t.date()).collect();
+        assert_eq!(dates, vec![0, 15 * SECOND, 30 * SECOND, 75 * SECOND]);
+        assert!(marked[2].temperatures()[0].is_missing());
+        assert_eq!(
+            serde_json::to_value(&marked[2].temperatures()[0]).unwrap()["value"],
+            serde_json::Value::Null
+        );
+    }
+}
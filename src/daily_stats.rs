@@ -0,0 +1,209 @@
+use crate::database::DatabaseAccessError;
+use crate::local_time::midnight;
+use crate::storage::Storage;
+
+use chrono::{Days, NaiveDate, TimeZone};
+use serde::Serialize;
+
+/// Summary of one sensor on one local calendar day.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DailyStats {
+    /// Calendar day as `YYYY-MM-DD`
+    day: String,
+    sensor: String,
+    min: f32,
+    max: f32,
+    mean: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time_above_threshold_seconds: Option<u64>,
+    /// Share of the day, or of its requested part, covered by temperatures
+    coverage_percent: f32,
+}
+
+/// A local calendar day clipped to a requested range, in epoch milliseconds.
+#[derive(Debug, PartialEq)]
+pub struct Day {
+    pub date: NaiveDate,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Local calendar days between `from` and `to`, whose length differs from
+/// 24 hours on days the clocks change.
+pub fn local_days<Tz: TimeZone>(from: u64, to: u64, timezone: &Tz) -> Vec<Day> {
+    let mut days = vec![];
+    let mut date = match timezone.timestamp_millis_opt(from as i64).earliest() {
+        Some(start) => start.date_naive(),
+        None => return days,
+    };
+
+    loop {
+        let next = match date.checked_add_days(Days::new(1)) {
+            Some(next) => next,
+            None => return days,
+        };
+        let start = midnight(date, timezone).max(from);
+        let end = midnight(next, timezone).min(to);
+
+        // midnight is 0 for days which can't be represented
+        if start >= to || end <= start {
+            return days;
+        }
+
+        days.push(Day { date, start, end });
+        date = next;
+    }
+}
+
+/// Statistics of each sensor, or only of `sensor`, per local calendar day.
+pub fn daily_stats<Tz: TimeZone>(
+    db: &dyn Storage,
+    from: u64,
+    to: u64,
+    sensor: Option<&str>,
+    threshold: Option<f32>,
+    timezone: &Tz,
+) -> Result<Vec<DailyStats>, DatabaseAccessError> {
+    let mut stats = vec![];
+
+    for day in local_days(from, to, timezone) {
+        let day_seconds = (day.end - day.start) as f32 / 1000.0;
+
+        for range in db.load_range_stats(day.start, day.end, sensor, threshold)? {
+            stats.push(DailyStats {
+                day: day.date.format("%Y-%m-%d").to_string(),
+                sensor: range.name,
+                min: range.min,
+                max: range.max,
+                mean: range.mean,
+                time_above_threshold_seconds: threshold.map(|_| range.above_seconds),
+                coverage_percent: (range.covered_seconds as f32 / day_seconds * 100.0).min(100.0),
+            });
+        }
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_storage::MemoryStorage;
+    use crate::temperature_recorder::{
+        now_millis, Temperature, TemperaturesByTime, MILLIS_PER_DAY,
+    };
+    use chrono::{FixedOffset, Utc};
+
+    #[test]
+    fn splits_range_into_local_days() {
+        let hour = 3_600_000;
+        let timezone = FixedOffset::east_opt(2 * 3600).unwrap();
+
+        let days = local_days(0, 2 * MILLIS_PER_DAY, &timezone);
+
+        let ranges: Vec<(String, u64, u64)> = days
+            .into_iter()
+            .map(|day| (day.date.to_string(), day.start, day.end))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![
+                (String::from("1970-01-01"), 0, 22 * hour),
+                (String::from("1970-01-02"), 22 * hour, 46 * hour),
+                (String::from("1970-01-03"), 46 * hour, 48 * hour),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_the_last_representable_day() {
+        let last = NaiveDate::MAX
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc()
+            .timestamp_millis() as u64;
+
+        let days = local_days(last - MILLIS_PER_DAY, u64::MAX, &Utc);
+
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].end, last);
+    }
+
+    #[test]
+    fn summarises_sensors_per_day() {
+        let db = MemoryStorage::new();
+        let now = now_millis().unwrap();
+        let yesterday = now - now % MILLIS_PER_DAY - MILLIS_PER_DAY;
+
+        for (offset, value) in [(1, 20.0), (2, 30.0)] {
+            let temperatures = vec![Temperature::new(String::from("flow"), value)];
+            db.save_temperatures(TemperaturesByTime::new(
+                yesterday + offset * 3_600_000,
+                temperatures,
+            ))
+            .unwrap();
+        }
+
+        let stats = daily_stats(
+            &db,
+            yesterday,
+            yesterday + MILLIS_PER_DAY,
+            Some("flow"),
+            Some(25.0),
+            &Utc,
+        )
+        .unwrap();
+
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].min, 20.0);
+        assert_eq!(stats[0].max, 30.0);
+        assert_eq!(stats[0].mean, 25.0);
+        // two samples of the default 15 second interval
+        assert_eq!(stats[0].time_above_threshold_seconds, Some(15));
+        assert_eq!(stats[0].coverage_percent, 30.0 / 86400.0 * 100.0);
+    }
+
+    /// Stats of `date` in Berlin with a reading every hour of its first
+    /// `hours` hours.
+    fn berlin_day_with_hourly_readings(date: &str, hours: u64) -> (Day, Vec<DailyStats>) {
+        let hour = 3_600_000;
+        let berlin = chrono_tz::Europe::Berlin;
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap();
+        let start = midnight(date, &berlin);
+        let end = midnight(date.succ_opt().unwrap(), &berlin);
+
+        let db = MemoryStorage::new();
+        for reading in (0..hours).map(|hours| start + hours * hour) {
+            let temperatures = vec![Temperature::new(String::from("flow"), 40.0)];
+            db.save_temperatures(TemperaturesByTime::new(reading, temperatures))
+                .unwrap();
+        }
+        db.update_rollups().unwrap();
+
+        let mut days = local_days(start, end, &berlin);
+        assert_eq!(days.len(), 1);
+        let stats = daily_stats(&db, start, end, Some("flow"), None, &berlin).unwrap();
+        (days.remove(0), stats)
+    }
+
+    #[test]
+    fn summarises_the_23_hours_of_the_day_clocks_go_forward() {
+        let (day, stats) = berlin_day_with_hourly_readings("2024-03-31", 23);
+
+        assert_eq!(day.end - day.start, 23 * 3_600_000);
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].day, "2024-03-31");
+        assert_eq!(stats[0].coverage_percent, 100.0);
+    }
+
+    #[test]
+    fn summarises_the_25_hours_of_the_day_clocks_go_back() {
+        let (day, stats) = berlin_day_with_hourly_readings("2024-10-27", 24);
+
+        assert_eq!(day.end - day.start, 25 * 3_600_000);
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].day, "2024-10-27");
+        // 24 of 25 hours, a day of 24 hours would be covered completely
+        assert!((stats[0].coverage_percent - 96.0).abs() < 0.01);
+    }
+}
@@ -1,14 +1,62 @@
-use crate::temperature_recorder::{RecorderConfig, Temperature, TemperaturesByTime};
-use rusqlite::Connection;
+use crate::audit::{AuditAction, AuditEntry};
+use crate::auth::{ApiToken, Scope};
+use crate::burner_cycles::BurnerCycle;
+use crate::sample_filter::{RejectReason, RejectedTemperature};
+use crate::storage::{IntervalChange, OutboxBatch, RangeStats, Storage};
+use crate::temperature_recorder::{
+    now_millis, RecorderConfig, Resolution, Temperature, TemperaturesByTime,
+};
+use chrono_tz::Tz;
+use filesize::PathExt;
+use rusqlite::types::Type;
+use rusqlite::{Connection, OpenFlags, Row};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTimeError};
 
+const SCHEMA_VERSION: u32 = 1;
+
+/// Idle read connections kept around for reuse; further concurrent readers
+/// get a connection of their own which is closed afterwards.
+const MAX_IDLE_READERS: usize = 4;
+
+/// SQLite storage shared by the API and the recorder scheduler.
+///
+/// All writes go through a single connection, reads use a small pool of
+/// read-only connections which, thanks to WAL mode, never wait for a write.
 pub struct Database {
-    connection: Connection,
+    path: String,
+    writer: Mutex<Connection>,
+    readers: Mutex<Vec<Connection>>,
+    counters: AccessCounters,
+}
+
+#[derive(Default)]
+struct AccessCounters {
+    writes: AtomicU64,
+    write_wait_micros_total: AtomicU64,
+    write_wait_micros_max: AtomicU64,
+    reads: AtomicU64,
+    read_connections_opened: AtomicU64,
+}
+
+/// Snapshot of how often and how long the database was waited for.
+#[derive(Serialize, Debug, Default)]
+pub struct DatabaseStats {
+    writes: u64,
+    write_wait_micros_total: u64,
+    write_wait_micros_max: u64,
+    reads: u64,
+    read_connections_opened: u64,
 }
 
 #[derive(Debug)]
 pub enum DatabaseInitError {
     Open(rusqlite::Error),
     CreateDatabases(rusqlite::Error),
+    Migrate(rusqlite::Error),
     InsertDefaultState(rusqlite::Error),
 }
 
@@ -17,13 +65,30 @@ pub enum DatabaseAccessError {
     Read(rusqlite::Error),
     Write(rusqlite::Error),
     Delete(rusqlite::Error),
+    Date(SystemTimeError),
+    Lock(String),
     NoConfigFound,
 }
 
 impl Database {
     pub fn new() -> Result<Self, DatabaseInitError> {
         // TODO get path from arguments
-        let connection = Connection::open("boiler-watch.db").map_err(DatabaseInitError::Open)?;
+        Self::open("boiler-watch.db")
+    }
+
+    pub fn open(path: &str) -> Result<Self, DatabaseInitError> {
+        let connection = Connection::open(path).map_err(DatabaseInitError::Open)?;
+
+        // WAL keeps readers from blocking on the writer and, together with
+        // synchronous=normal, only syncs on checkpoints instead of on every
+        // commit, which matters on SD cards.
+        connection
+            .pragma_update(None, "journal_mode", "wal")
+            .map_err(DatabaseInitError::Open)?;
+
+        connection
+            .pragma_update(None, "synchronous", "normal")
+            .map_err(DatabaseInitError::Open)?;
 
         connection
             .execute(
@@ -34,6 +99,26 @@ impl Database {
             )
             .map_err(DatabaseInitError::CreateDatabases)?;
 
+        Self::add_column_if_missing(
+            &connection,
+            "recorder_config",
+            "keep_days_5m",
+            &format!(
+                "integer not null default {}",
+                RecorderConfig::default_keep_days_5m()
+            ),
+        )?;
+
+        Self::add_column_if_missing(
+            &connection,
+            "recorder_config",
+            "keep_days_1h",
+            &format!(
+                "integer not null default {}",
+                RecorderConfig::default_keep_days_1h()
+            ),
+        )?;
+
         connection
             .execute(
                 "insert into recorder_config (interval_seconds, keep_days)
@@ -43,6 +128,25 @@ impl Database {
             )
             .map_err(DatabaseInitError::InsertDefaultState)?;
 
+        // intervals before the history was kept are assumed to be the current one
+        connection
+            .execute(
+                "create table if not exists recorder_intervals (
+                since integer primary key,
+                interval_seconds integer not null )",
+                (),
+            )
+            .map_err(DatabaseInitError::CreateDatabases)?;
+
+        connection
+            .execute(
+                "insert into recorder_intervals (since, interval_seconds)
+                select 0, interval_seconds from recorder_config
+                where not exists (select * from recorder_intervals)",
+                (),
+            )
+            .map_err(DatabaseInitError::InsertDefaultState)?;
+
         connection
             .execute(
                 "create table if not exists temperatures (
@@ -53,14 +157,217 @@ impl Database {
             )
             .map_err(DatabaseInitError::CreateDatabases)?;
 
-        Ok(Database { connection })
+        connection
+            .execute(
+                "create index if not exists temperatures_date on temperatures (date)",
+                (),
+            )
+            .map_err(DatabaseInitError::CreateDatabases)?;
+
+        connection
+            .execute(
+                "create table if not exists rejected_temperatures (
+                name string not null,
+                value real not null,
+                date integer not null,
+                reason text not null )",
+                (),
+            )
+            .map_err(DatabaseInitError::CreateDatabases)?;
+
+        connection
+            .execute(
+                "create table if not exists burner_cycles (
+                start integer primary key,
+                end integer not null )",
+                (),
+            )
+            .map_err(DatabaseInitError::CreateDatabases)?;
+
+        connection
+            .execute(
+                "create table if not exists audit_log (
+                date integer not null,
+                action text not null,
+                client text,
+                details text not null )",
+                (),
+            )
+            .map_err(DatabaseInitError::CreateDatabases)?;
+
+        Self::add_column_if_missing(&connection, "audit_log", "token", "text")?;
+
+        connection
+            .execute(
+                "create table if not exists api_tokens (
+                name text primary key,
+                hash text not null unique,
+                scope text not null,
+                created integer not null )",
+                (),
+            )
+            .map_err(DatabaseInitError::CreateDatabases)?;
+
+        connection
+            .execute(
+                "create table if not exists outbox (
+                id integer primary key autoincrement,
+                body text not null )",
+                (),
+            )
+            .map_err(DatabaseInitError::CreateDatabases)?;
+
+        for resolution in Resolution::ROLLUPS {
+            connection
+                .execute(
+                    &format!(
+                        "create table if not exists {} (
+                        name string not null,
+                        value_min real not null,
+                        value_avg real not null,
+                        value_max real not null,
+                        samples integer not null,
+                        date integer not null,
+                        primary key (name, date) )",
+                        resolution.table()
+                    ),
+                    (),
+                )
+                .map_err(DatabaseInitError::CreateDatabases)?;
+        }
+
+        Self::migrate(&connection)?;
+
+        Ok(Database {
+            path: path.to_owned(),
+            writer: Mutex::new(connection),
+            readers: Mutex::new(vec![]),
+            counters: AccessCounters::default(),
+        })
+    }
+
+    fn write<T, F>(&self, write: F) -> Result<T, DatabaseAccessError>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, DatabaseAccessError>,
+    {
+        let wait_start = Instant::now();
+        let mut connection = self.writer.lock().map_err(|err| {
+            log::error!("Error locking database writer: {}", err);
+            DatabaseAccessError::Lock(err.to_string())
+        })?;
+        let wait_micros = wait_start.elapsed().as_micros() as u64;
+
+        self.counters.writes.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .write_wait_micros_total
+            .fetch_add(wait_micros, Ordering::Relaxed);
+        self.counters
+            .write_wait_micros_max
+            .fetch_max(wait_micros, Ordering::Relaxed);
+
+        write(&mut connection)
+    }
+
+    fn read<T, F>(&self, read: F) -> Result<T, DatabaseAccessError>
+    where
+        F: FnOnce(&Connection) -> Result<T, DatabaseAccessError>,
+    {
+        self.counters.reads.fetch_add(1, Ordering::Relaxed);
+
+        let idle = self
+            .readers
+            .lock()
+            .map_err(|err| DatabaseAccessError::Lock(err.to_string()))?
+            .pop();
+
+        let connection = match idle {
+            Some(connection) => connection,
+            None => {
+                self.counters
+                    .read_connections_opened
+                    .fetch_add(1, Ordering::Relaxed);
+                Connection::open_with_flags(
+                    &self.path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )
+                .map_err(DatabaseAccessError::Read)?
+            }
+        };
+
+        let result = read(&connection);
+
+        let mut readers = self
+            .readers
+            .lock()
+            .map_err(|err| DatabaseAccessError::Lock(err.to_string()))?;
+        if readers.len() < MAX_IDLE_READERS {
+            readers.push(connection);
+        }
+
+        result
     }
 
-    pub fn load_recorder_config(&self) -> Result<RecorderConfig, DatabaseAccessError> {
-        let mut statement = self
-            .connection
+    fn migrate(connection: &Connection) -> Result<(), DatabaseInitError> {
+        let version: u32 = connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(DatabaseInitError::Migrate)?;
+
+        if version < 1 {
+            // Values used to be inserted as strings rounded to two decimals
+            log::info!("Migrating temperature values to numbers");
+            connection
+                .execute(
+                    "update temperatures set value = cast(value as real)
+                    where typeof(value) != 'real'",
+                    (),
+                )
+                .map_err(DatabaseInitError::Migrate)?;
+        }
+
+        if version < SCHEMA_VERSION {
+            connection
+                .pragma_update(None, "user_version", SCHEMA_VERSION)
+                .map_err(DatabaseInitError::Migrate)?;
+        }
+
+        Ok(())
+    }
+
+    fn add_column_if_missing(
+        connection: &Connection,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<(), DatabaseInitError> {
+        let mut statement = connection
+            .prepare(&format!("select name from pragma_table_info('{}')", table))
+            .map_err(DatabaseInitError::Migrate)?;
+
+        let columns = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(DatabaseInitError::Migrate)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(DatabaseInitError::Migrate)?;
+
+        if !columns.iter().any(|c| c == column) {
+            log::info!("Adding column {} to table {}", column, table);
+            connection
+                .execute(
+                    &format!("alter table {} add column {} {}", table, column, definition),
+                    (),
+                )
+                .map_err(DatabaseInitError::Migrate)?;
+        }
+
+        Ok(())
+    }
+
+    fn load_recorder_config_with(
+        connection: &Connection,
+    ) -> Result<RecorderConfig, DatabaseAccessError> {
+        let mut statement = connection
             .prepare(
-                "select interval_seconds, keep_days 
+                "select interval_seconds, keep_days, keep_days_5m, keep_days_1h
                 from recorder_config",
             )
             .map_err(DatabaseAccessError::Read)?;
@@ -69,7 +376,14 @@ impl Database {
             .query_map([], |row| {
                 let interval_seconds = row.get(0)?;
                 let keep_days = row.get(1)?;
-                return Ok(RecorderConfig::new(interval_seconds, keep_days));
+                let keep_days_5m = row.get(2)?;
+                let keep_days_1h = row.get(3)?;
+                Ok(RecorderConfig::new(
+                    interval_seconds,
+                    keep_days,
+                    keep_days_5m,
+                    keep_days_1h,
+                ))
             })
             .map_err(DatabaseAccessError::Read)?;
 
@@ -80,171 +394,1072 @@ impl Database {
         }
     }
 
-    pub fn save_recorder_config(
+    fn load_grouped_by_date<F>(
+        &self,
+        sql: &str,
+        since: u64,
+        until: Option<u64>,
+        to_temperature: F,
+    ) -> Result<Vec<TemperaturesByTime>, DatabaseAccessError>
+    where
+        F: Fn(&Row) -> rusqlite::Result<Temperature>,
+    {
+        let rows = self.read(|connection| {
+            let mut statement = connection.prepare(sql).map_err(DatabaseAccessError::Read)?;
+
+            let rows = statement
+                .query_map((since, until), |row| {
+                    let date: u64 = row.get(0)?;
+                    Ok((date, to_temperature(row)?))
+                })
+                .map_err(DatabaseAccessError::Read)?
+                .collect::<Result<Vec<(u64, Temperature)>, _>>()
+                .map_err(DatabaseAccessError::Read)?;
+
+            Ok(rows)
+        })?;
+
+        let mut temperatures_by_time: Vec<TemperaturesByTime> = vec![];
+        let mut current: Option<(u64, Vec<Temperature>)> = None;
+
+        for (date, temperature) in rows {
+            match current.as_mut() {
+                Some((current_date, temperatures)) if *current_date == date => {
+                    temperatures.push(temperature)
+                }
+                _ => {
+                    if let Some((current_date, temperatures)) = current.take() {
+                        temperatures_by_time
+                            .push(TemperaturesByTime::new(current_date, temperatures));
+                    }
+                    current = Some((date, vec![temperature]));
+                }
+            }
+        }
+
+        if let Some((current_date, temperatures)) = current {
+            temperatures_by_time.push(TemperaturesByTime::new(current_date, temperatures));
+        }
+
+        Ok(temperatures_by_time)
+    }
+
+    /// Temperatures since `since` and before `until`, if given, from the
+    /// finest resolution whose retention still covers `since`.
+    fn load_temperatures(
+        &self,
+        since: u64,
+        until: Option<u64>,
+    ) -> Result<Vec<TemperaturesByTime>, DatabaseAccessError> {
+        let config = self.load_recorder_config()?;
+        let now = now_millis().map_err(DatabaseAccessError::Date)?;
+        let resolution = config.resolution_since(since, now);
+
+        log::debug!("Loading temperatures since {} from {:?}", since, resolution);
+
+        match resolution {
+            Resolution::Raw => self.load_grouped_by_date(
+                "select date, name, value
+                from temperatures where date >= ?1 and (?2 is null or date < ?2)
+                order by date",
+                since,
+                until,
+                |row| Ok(Temperature::new(row.get(1)?, row.get(2)?)),
+            ),
+            rollup => {
+                let bucket_start = since - since % rollup.bucket_millis();
+                self.load_grouped_by_date(
+                    &format!(
+                        "select date, name, value_avg, value_min, value_max
+                        from {} where date >= ?1 and (?2 is null or date < ?2)
+                        order by date",
+                        rollup.table()
+                    ),
+                    bucket_start,
+                    until,
+                    |row| {
+                        Ok(Temperature::with_range(
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                        ))
+                    },
+                )
+            }
+        }
+    }
+
+    fn load_youngest_date_of_temperatures(&self) -> Result<Option<u64>, DatabaseAccessError> {
+        self.read(|connection| {
+            connection
+                .query_row("select max(date) from temperatures", [], |row| row.get(0))
+                .map_err(DatabaseAccessError::Read)
+        })
+    }
+}
+
+impl Storage for Database {
+    fn stats(&self) -> DatabaseStats {
+        DatabaseStats {
+            writes: self.counters.writes.load(Ordering::Relaxed),
+            write_wait_micros_total: self
+                .counters
+                .write_wait_micros_total
+                .load(Ordering::Relaxed),
+            write_wait_micros_max: self.counters.write_wait_micros_max.load(Ordering::Relaxed),
+            reads: self.counters.reads.load(Ordering::Relaxed),
+            read_connections_opened: self
+                .counters
+                .read_connections_opened
+                .load(Ordering::Relaxed),
+        }
+    }
+
+    fn size_on_disk(&self) -> Option<u64> {
+        Path::new(&self.path)
+            .size_on_disk()
+            .map_err(|error| log::error!("Error getting database file size: {:?}", error))
+            .ok()
+    }
+
+    fn load_recorder_config(&self) -> Result<RecorderConfig, DatabaseAccessError> {
+        self.read(Self::load_recorder_config_with)
+    }
+
+    fn save_recorder_config(
         &self,
         config: RecorderConfig,
     ) -> Result<RecorderConfig, DatabaseAccessError> {
-        self.connection
-            .execute("delete from recorder_config", ())
-            .map_err(DatabaseAccessError::Write)?;
+        let now = now_millis().map_err(DatabaseAccessError::Date)?;
 
-        self.connection
-            .execute(
-                "insert into recorder_config (interval_seconds, keep_days) values (?1, ?2)",
-                (&config.interval_seconds, &config.keep_days),
-            )
-            .map_err(DatabaseAccessError::Write)?;
+        self.write(|connection| {
+            let transaction = connection
+                .transaction()
+                .map_err(DatabaseAccessError::Write)?;
+
+            transaction
+                .execute("delete from recorder_config", ())
+                .map_err(DatabaseAccessError::Write)?;
+
+            transaction
+                .execute(
+                    "insert into recorder_config (interval_seconds, keep_days, keep_days_5m, keep_days_1h)
+                    values (?1, ?2, ?3, ?4)",
+                    (
+                        &config.interval_seconds,
+                        &config.keep_days,
+                        &config.keep_days_5m,
+                        &config.keep_days_1h,
+                    ),
+                )
+                .map_err(DatabaseAccessError::Write)?;
+
+            transaction
+                .execute(
+                    "insert or replace into recorder_intervals (since, interval_seconds)
+                    select ?1, ?2
+                    where ?2 is not (
+                        select interval_seconds from recorder_intervals
+                        order by since desc limit 1)",
+                    (now, &config.interval_seconds),
+                )
+                .map_err(DatabaseAccessError::Write)?;
+
+            transaction.commit().map_err(DatabaseAccessError::Write)
+        })?;
 
         Ok(config)
     }
 
-    pub fn load_temperatures_since(
+    fn load_interval_history(&self) -> Result<Vec<IntervalChange>, DatabaseAccessError> {
+        self.read(|connection| {
+            let mut statement = connection
+                .prepare("select since, interval_seconds from recorder_intervals order by since")
+                .map_err(DatabaseAccessError::Read)?;
+
+            let history = statement
+                .query_map([], |row| {
+                    Ok(IntervalChange {
+                        since: row.get(0)?,
+                        interval_seconds: row.get(1)?,
+                    })
+                })
+                .map_err(DatabaseAccessError::Read)?
+                .collect::<Result<Vec<IntervalChange>, _>>()
+                .map_err(DatabaseAccessError::Read)?;
+
+            Ok(history)
+        })
+    }
+
+    fn load_temperatures_since(
         &self,
         since: u64,
     ) -> Result<Vec<TemperaturesByTime>, DatabaseAccessError> {
-        let dates = self.load_dates_distinct_since(since)?;
+        self.load_temperatures(since, None)
+    }
+
+    fn load_temperatures_between(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<TemperaturesByTime>, DatabaseAccessError> {
+        self.load_temperatures(from, Some(to))
+    }
 
-        log::debug!("Dates: {:?}", dates);
+    fn load_last_temperature(&self) -> Result<Option<TemperaturesByTime>, DatabaseAccessError> {
+        let date_max = match self.load_youngest_date_of_temperatures()? {
+            Some(d) => d,
+            None => return Ok(None),
+        };
 
-        return dates
-            .iter()
-            .map(|date| {
-                let mut statement = self
-                    .connection
-                    .prepare(
-                        "select name, value
-                        from temperatures where date is ?1",
+        log::debug!("date_max: {}", date_max);
+
+        let temperatures = self.read(|connection| {
+            let mut statement = connection
+                .prepare(
+                    "select name, value
+                    from temperatures where date = ?1",
+                )
+                .map_err(DatabaseAccessError::Read)?;
+
+            let temperatures = statement
+                .query_map([date_max], |row| {
+                    let name = row.get(0)?;
+                    let value = row.get(1)?;
+                    Ok(Temperature::new(name, value))
+                })
+                .map_err(DatabaseAccessError::Read)?
+                .collect::<Result<Vec<Temperature>, _>>()
+                .map_err(DatabaseAccessError::Read)?;
+
+            Ok(temperatures)
+        })?;
+
+        Ok(Some(TemperaturesByTime::new(date_max, temperatures)))
+    }
+
+    /// Saves all temperatures of one timestamp in a single transaction.
+    fn save_temperatures(
+        &self,
+        temperatures_by_time: TemperaturesByTime,
+    ) -> Result<(), DatabaseAccessError> {
+        self.write(|connection| {
+            let transaction = connection
+                .transaction()
+                .map_err(DatabaseAccessError::Write)?;
+
+            {
+                let mut statement = transaction
+                    .prepare_cached(
+                        "insert into temperatures (date, name, value) values (?1, ?2, ?3)",
                     )
-                    .map_err(DatabaseAccessError::Read)?;
+                    .map_err(DatabaseAccessError::Write)?;
+
+                for temperature in temperatures_by_time.temperatures() {
+                    statement
+                        .execute((
+                            temperatures_by_time.date(),
+                            temperature.name(),
+                            temperature.value(),
+                        ))
+                        .map_err(DatabaseAccessError::Write)?;
+                }
+            }
+
+            transaction.commit().map_err(DatabaseAccessError::Write)
+        })
+    }
 
-                let temperatures = statement
-                    .query_map([date], |row| {
-                        let name = row.get(0)?;
-                        let value = row.get(1)?;
-                        return Ok(Temperature::new(name, value));
+    /// Aggregates raw temperatures into the rollup tables, starting at the
+    /// youngest bucket of each rollup so a partially filled bucket is
+    /// recomputed and a fresh rollup table is backfilled from all raw data.
+    fn update_rollups(&self) -> Result<(), DatabaseAccessError> {
+        self.write(|connection| {
+            for resolution in Resolution::ROLLUPS {
+                connection
+                    .execute(
+                    &format!(
+                        "insert or replace into {table}
+                        (name, date, value_min, value_avg, value_max, samples)
+                        select name, (date / ?1) * ?1 as bucket, min(value), avg(value), max(value), count(*)
+                        from temperatures
+                        where date >= (select coalesce(max(date), 0) from {table})
+                        group by name, bucket",
+                        table = resolution.table()
+                    ),
+                    [resolution.bucket_millis()],
+                )
+                .map_err(DatabaseAccessError::Write)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn delete_old_temperatures(&self, timezone: &Tz) -> Result<usize, DatabaseAccessError> {
+        let config = self.load_recorder_config()?;
+        let now = now_millis().map_err(DatabaseAccessError::Date)?;
+        let cutoff = |resolution| config.retention_cutoff(resolution, now, timezone);
+
+        self.write(|connection| {
+            let deleted = Resolution::ALL.iter().try_fold(0, |deleted, resolution| {
+                connection
+                    .execute(
+                        &format!("delete from {} where date < ?1", resolution.table()),
+                        [cutoff(*resolution)],
+                    )
+                    .map(|count| deleted + count)
+                    .map_err(DatabaseAccessError::Delete)
+            })?;
+
+            let deleted_rejected = connection
+                .execute(
+                    "delete from rejected_temperatures where date < ?1",
+                    [cutoff(Resolution::Raw)],
+                )
+                .map_err(DatabaseAccessError::Delete)?;
+
+            // cycles are few, so they are kept as long as the coarsest rollup
+            let deleted_cycles = connection
+                .execute(
+                    "delete from burner_cycles where start < ?1",
+                    [cutoff(Resolution::Hourly)],
+                )
+                .map_err(DatabaseAccessError::Delete)?;
+
+            Ok(deleted + deleted_rejected + deleted_cycles)
+        })
+    }
+
+    fn save_rejected_temperatures(
+        &self,
+        rejected: &[RejectedTemperature],
+    ) -> Result<(), DatabaseAccessError> {
+        self.write(|connection| {
+            let transaction = connection
+                .transaction()
+                .map_err(DatabaseAccessError::Write)?;
+
+            {
+                let mut statement = transaction
+                    .prepare_cached(
+                        "insert into rejected_temperatures (date, name, value, reason)
+                        values (?1, ?2, ?3, ?4)",
+                    )
+                    .map_err(DatabaseAccessError::Write)?;
+
+                for temperature in rejected {
+                    statement
+                        .execute((
+                            temperature.date(),
+                            temperature.name(),
+                            temperature.value(),
+                            temperature.reason().as_str(),
+                        ))
+                        .map_err(DatabaseAccessError::Write)?;
+                }
+            }
+
+            transaction.commit().map_err(DatabaseAccessError::Write)
+        })
+    }
+
+    fn load_rejected_temperatures_since(
+        &self,
+        since: u64,
+    ) -> Result<Vec<RejectedTemperature>, DatabaseAccessError> {
+        self.read(|connection| {
+            let mut statement = connection
+                .prepare(
+                    "select name, value, date, reason
+                    from rejected_temperatures where date >= ?1
+                    order by date",
+                )
+                .map_err(DatabaseAccessError::Read)?;
+
+            let rejected = statement
+                .query_map([since], |row| {
+                    let reason: String = row.get(3)?;
+                    let reason = RejectReason::parse(&reason)
+                        .ok_or_else(|| rusqlite::Error::InvalidColumnType(3, reason, Type::Text))?;
+                    Ok(RejectedTemperature::new(
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        reason,
+                    ))
+                })
+                .map_err(DatabaseAccessError::Read)?
+                .collect::<Result<Vec<RejectedTemperature>, _>>()
+                .map_err(DatabaseAccessError::Read)?;
+
+            Ok(rejected)
+        })
+    }
+
+    fn load_range_stats(
+        &self,
+        from: u64,
+        to: u64,
+        sensor: Option<&str>,
+        threshold: Option<f32>,
+    ) -> Result<Vec<RangeStats>, DatabaseAccessError> {
+        let config = self.load_recorder_config()?;
+        let now = now_millis().map_err(DatabaseAccessError::Date)?;
+        let resolution = config.resolution_since(from, now);
+
+        // raw temperatures cover the interval used when they were recorded,
+        // rollup buckets are weighted by their samples for the mean and
+        // count as a whole for the time above the threshold
+        let sql = match resolution {
+            Resolution::Raw => String::from(
+                "select name, min(value), max(value), avg(value), sum(interval),
+                coalesce(sum((value > ?3) * interval), 0)
+                from (
+                    select name, value, coalesce(
+                        (select interval_seconds from recorder_intervals
+                        where since <= date order by since desc limit 1),
+                        (select interval_seconds from recorder_intervals
+                        order by since limit 1)) as interval
+                    from temperatures
+                    where date >= ?1 and date < ?2 and (?4 is null or name = ?4) )
+                group by name order by name",
+            ),
+            rollup => format!(
+                "select name, min(value_min), max(value_max),
+                sum(value_avg * samples) / sum(samples), count(*) * {seconds},
+                coalesce(sum(value_avg > ?3), 0) * {seconds}
+                from {table}
+                where date >= ?1 and date < ?2 and (?4 is null or name = ?4)
+                group by name order by name",
+                seconds = rollup.bucket_millis() / 1000,
+                table = rollup.table()
+            ),
+        };
+
+        self.read(|connection| {
+            let mut statement = connection
+                .prepare(&sql)
+                .map_err(DatabaseAccessError::Read)?;
+
+            let stats = statement
+                .query_map((from, to, threshold, sensor), |row| {
+                    Ok(RangeStats {
+                        name: row.get(0)?,
+                        min: row.get(1)?,
+                        max: row.get(2)?,
+                        mean: row.get(3)?,
+                        covered_seconds: row.get(4)?,
+                        above_seconds: row.get(5)?,
                     })
-                    .map_err(DatabaseAccessError::Read)?
-                    .collect::<Result<Vec<Temperature>, _>>()
-                    .map_err(DatabaseAccessError::Read)?;
+                })
+                .map_err(DatabaseAccessError::Read)?
+                .collect::<Result<Vec<RangeStats>, _>>()
+                .map_err(DatabaseAccessError::Read)?;
 
-                let temperatures_by_time = TemperaturesByTime::new(date.to_owned(), temperatures);
+            Ok(stats)
+        })
+    }
 
-                Ok(temperatures_by_time)
-            })
-            .collect();
+    fn save_burner_cycle(&self, cycle: &BurnerCycle) -> Result<(), DatabaseAccessError> {
+        self.write(|connection| {
+            connection
+                .execute(
+                    "insert or replace into burner_cycles (start, end) values (?1, ?2)",
+                    [cycle.start(), cycle.end()],
+                )
+                .map_err(DatabaseAccessError::Write)?;
+            Ok(())
+        })
     }
 
-    pub fn load_last_temperature(&self) -> Result<Option<TemperaturesByTime>, DatabaseAccessError> {
-        let date_max_opt = self.load_youngest_date_of_temperatures()?;
-        let date_max: u64;
+    fn load_burner_cycles(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<BurnerCycle>, DatabaseAccessError> {
+        self.read(|connection| {
+            let mut statement = connection
+                .prepare(
+                    "select start, end from burner_cycles
+                    where start >= ?1 and start <= ?2
+                    order by start",
+                )
+                .map_err(DatabaseAccessError::Read)?;
 
-        if let Some(d) = date_max_opt {
-            date_max = d;
-        } else {
-            return Ok(None);
-        }
+            let cycles = statement
+                .query_map([from, to], |row| {
+                    Ok(BurnerCycle::new(row.get(0)?, row.get(1)?))
+                })
+                .map_err(DatabaseAccessError::Read)?
+                .collect::<Result<Vec<BurnerCycle>, _>>()
+                .map_err(DatabaseAccessError::Read)?;
 
-        log::debug!("date_max: {}", date_max);
+            Ok(cycles)
+        })
+    }
 
-        let mut statement = self
-            .connection
-            .prepare(
-                "select name, value
-                from temperatures where date = ?1",
-            )
-            .map_err(DatabaseAccessError::Read)?;
+    fn save_audit_entry(&self, entry: &AuditEntry) -> Result<(), DatabaseAccessError> {
+        self.write(|connection| {
+            connection
+                .execute(
+                    "insert into audit_log (date, action, client, token, details)
+                    values (?1, ?2, ?3, ?4, ?5)",
+                    (
+                        entry.date(),
+                        entry.action().as_str(),
+                        entry.client(),
+                        entry.token(),
+                        entry.details().to_string(),
+                    ),
+                )
+                .map_err(DatabaseAccessError::Write)?;
+            Ok(())
+        })
+    }
+
+    fn load_audit_entries(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<AuditEntry>, DatabaseAccessError> {
+        self.read(|connection| {
+            let mut statement = connection
+                .prepare(
+                    "select date, action, client, token, details from audit_log
+                    where date >= ?1 and date <= ?2
+                    order by date, rowid",
+                )
+                .map_err(DatabaseAccessError::Read)?;
+
+            let entries = statement
+                .query_map([from, to], |row| {
+                    let action: String = row.get(1)?;
+                    let action = AuditAction::parse(&action)
+                        .ok_or_else(|| rusqlite::Error::InvalidColumnType(1, action, Type::Text))?;
+                    let details: String = row.get(4)?;
+                    let details = serde_json::from_str(&details).map_err(|err| {
+                        rusqlite::Error::FromSqlConversionFailure(4, Type::Text, Box::new(err))
+                    })?;
+                    Ok(AuditEntry::new(row.get(0)?, action, row.get(2)?, details)
+                        .with_token(row.get(3)?))
+                })
+                .map_err(DatabaseAccessError::Read)?
+                .collect::<Result<Vec<AuditEntry>, _>>()
+                .map_err(DatabaseAccessError::Read)?;
+
+            Ok(entries)
+        })
+    }
+
+    fn save_api_token(&self, token: &ApiToken) -> Result<(), DatabaseAccessError> {
+        self.write(|connection| {
+            connection
+                .execute(
+                    "insert into api_tokens (name, hash, scope, created)
+                    values (?1, ?2, ?3, ?4)",
+                    (
+                        token.name(),
+                        token.hash(),
+                        token.scope().as_str(),
+                        token.created(),
+                    ),
+                )
+                .map_err(DatabaseAccessError::Write)?;
+            Ok(())
+        })
+    }
+
+    fn load_api_tokens(&self) -> Result<Vec<ApiToken>, DatabaseAccessError> {
+        self.read(|connection| {
+            let mut statement = connection
+                .prepare("select name, hash, scope, created from api_tokens order by created")
+                .map_err(DatabaseAccessError::Read)?;
+
+            let tokens = statement
+                .query_map([], |row| {
+                    let scope: String = row.get(2)?;
+                    let scope = Scope::parse(&scope)
+                        .ok_or_else(|| rusqlite::Error::InvalidColumnType(2, scope, Type::Text))?;
+                    Ok(ApiToken::new(row.get(0)?, row.get(1)?, scope, row.get(3)?))
+                })
+                .map_err(DatabaseAccessError::Read)?
+                .collect::<Result<Vec<ApiToken>, _>>()
+                .map_err(DatabaseAccessError::Read)?;
+
+            Ok(tokens)
+        })
+    }
+
+    fn delete_api_token(&self, name: &str) -> Result<usize, DatabaseAccessError> {
+        self.write(|connection| {
+            connection
+                .execute("delete from api_tokens where name = ?1", [name])
+                .map_err(DatabaseAccessError::Delete)
+        })
+    }
+
+    fn push_outbox(&self, body: &str) -> Result<(), DatabaseAccessError> {
+        self.write(|connection| {
+            connection
+                .execute("insert into outbox (body) values (?1)", [body])
+                .map_err(DatabaseAccessError::Write)?;
+            Ok(())
+        })
+    }
+
+    fn load_outbox(&self, limit: usize) -> Result<Vec<OutboxBatch>, DatabaseAccessError> {
+        self.read(|connection| {
+            let mut statement = connection
+                .prepare("select id, body from outbox order by id limit ?1")
+                .map_err(DatabaseAccessError::Read)?;
+
+            let batches = statement
+                .query_map([limit as u64], |row| {
+                    Ok(OutboxBatch {
+                        id: row.get(0)?,
+                        body: row.get(1)?,
+                    })
+                })
+                .map_err(DatabaseAccessError::Read)?
+                .collect::<Result<Vec<OutboxBatch>, _>>()
+                .map_err(DatabaseAccessError::Read)?;
+
+            Ok(batches)
+        })
+    }
+
+    fn delete_outbox(&self, id: u64) -> Result<usize, DatabaseAccessError> {
+        self.write(|connection| {
+            connection
+                .execute("delete from outbox where id <= ?1", [id])
+                .map_err(DatabaseAccessError::Delete)
+        })
+    }
+
+    fn trim_outbox(&self, keep: usize) -> Result<usize, DatabaseAccessError> {
+        self.write(|connection| {
+            connection
+                .execute(
+                    "delete from outbox where id not in (
+                    select id from outbox order by id desc limit ?1)",
+                    [keep as u64],
+                )
+                .map_err(DatabaseAccessError::Delete)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn open_temp_database() -> (TempDir, Database) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("boiler-watch.db");
+        let db = Database::open(path.to_str().unwrap()).unwrap();
+        (dir, db)
+    }
+
+    fn count_temperatures(db: &Database) -> usize {
+        db.read(|connection| {
+            connection
+                .query_row("select count(*) from temperatures", [], |row| row.get(0))
+                .map_err(DatabaseAccessError::Read)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn save_temperatures_stores_all_sensors() {
+        let (_dir, db) = open_temp_database();
+
+        let temperatures = vec![
+            Temperature::new(String::from("sensor1"), 21.5),
+            Temperature::new(String::from("sensor2"), 48.25),
+        ];
+        db.save_temperatures(TemperaturesByTime::new(1000, temperatures))
+            .unwrap();
+
+        assert_eq!(count_temperatures(&db), 2);
+    }
+
+    #[test]
+    fn save_temperatures_stores_numbers_without_rounding() {
+        let (_dir, db) = open_temp_database();
+
+        let temperatures = vec![Temperature::new(String::from("sensor1"), 21.4375)];
+        db.save_temperatures(TemperaturesByTime::new(1000, temperatures))
+            .unwrap();
 
-        let temperatures = statement
-            .query_map([date_max], |row| {
-                let name = row.get(0)?;
-                let value = row.get(1)?;
-                return Ok(Temperature::new(name, value));
+        let (value_type, value): (String, f64) = db
+            .read(|connection| {
+                connection
+                    .query_row("select typeof(value), value from temperatures", [], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })
+                    .map_err(DatabaseAccessError::Read)
             })
-            .map_err(DatabaseAccessError::Read)?
-            .collect::<Result<Vec<Temperature>, _>>()
-            .map_err(DatabaseAccessError::Read)?;
+            .unwrap();
 
-        return Ok(Some(TemperaturesByTime::new(date_max, temperatures)));
+        assert_eq!(value_type, "real");
+        assert_eq!(value, 21.4375);
     }
 
-    fn load_dates_distinct_since(&self, since: u64) -> Result<Vec<u64>, DatabaseAccessError> {
-        let mut statement = self
-            .connection
-            .prepare(
-                "select distinct date from temperatures
-                    where date >= ?1",
-            )
-            .map_err(DatabaseAccessError::Read)?;
+    #[test]
+    fn open_migrates_values_stored_as_strings() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("boiler-watch.db");
+        let path = path.to_str().unwrap();
+
+        {
+            // without affinity the column keeps the strings as inserted
+            let legacy = Connection::open(path).unwrap();
+            legacy
+                .execute(
+                    "create table temperatures (
+                    name string not null,
+                    value not null,
+                    date integer not null )",
+                    (),
+                )
+                .unwrap();
+            legacy
+                .execute(
+                    "insert into temperatures (date, name, value)
+                    values (1000, 'sensor1', '21.44'), (1000, 'sensor2', '-3.50')",
+                    (),
+                )
+                .unwrap();
+        }
 
-        let dates = statement
-            .query_map([since], |row| {
-                let date: u64 = row.get(0)?;
-                Ok(date)
+        let db = Database::open(path).unwrap();
+
+        let values: Vec<(String, f64)> = db
+            .read(|connection| {
+                let mut statement = connection
+                    .prepare("select typeof(value), value from temperatures order by name")
+                    .map_err(DatabaseAccessError::Read)?;
+                let values = statement
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .map_err(DatabaseAccessError::Read)?
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(DatabaseAccessError::Read)?;
+                Ok(values)
             })
-            .map_err(DatabaseAccessError::Read)?
-            .collect::<Result<Vec<u64>, _>>()
-            .map_err(DatabaseAccessError::Read)?;
+            .unwrap();
+        let version: u32 = db
+            .read(|connection| {
+                connection
+                    .pragma_query_value(None, "user_version", |row| row.get(0))
+                    .map_err(DatabaseAccessError::Read)
+            })
+            .unwrap();
 
-        Ok(dates)
+        assert_eq!(
+            values,
+            vec![(String::from("real"), 21.44), (String::from("real"), -3.5)]
+        );
+        assert_eq!(version, SCHEMA_VERSION);
     }
 
-    fn load_youngest_date_of_temperatures(&self) -> Result<Option<u64>, DatabaseAccessError> {
-        let mut statement = self
-            .connection
-            .prepare("select max(date) from temperatures")
-            .map_err(DatabaseAccessError::Read)?;
+    #[test]
+    fn save_burner_cycle_replaces_the_cycle_with_the_same_start() {
+        let (_dir, db) = open_temp_database();
 
-        let mut date_iter = statement
-            .query_map([], |row| {
-                let max_date: u64 = row.get(0)?;
-                Ok(max_date as u64)
+        db.save_burner_cycle(&BurnerCycle::new(1_000, 2_000))
+            .unwrap();
+        db.save_burner_cycle(&BurnerCycle::new(1_000, 3_000))
+            .unwrap();
+
+        assert_eq!(
+            db.load_burner_cycles(0, 10_000).unwrap(),
+            vec![BurnerCycle::new(1_000, 3_000)]
+        );
+    }
+
+    #[test]
+    fn save_temperatures_is_all_or_nothing() {
+        let (_dir, db) = open_temp_database();
+
+        db.write(|connection| {
+            connection
+                .execute(
+                    "create trigger reject_broken before insert on temperatures
+                    when new.name = 'broken'
+                    begin select raise(abort, 'broken sensor'); end",
+                    (),
+                )
+                .map_err(DatabaseAccessError::Write)
+        })
+        .unwrap();
+
+        let temperatures = vec![
+            Temperature::new(String::from("sensor1"), 21.5),
+            Temperature::new(String::from("broken"), 48.25),
+            Temperature::new(String::from("sensor3"), 60.0),
+        ];
+        let result = db.save_temperatures(TemperaturesByTime::new(1000, temperatures));
+
+        assert!(matches!(result, Err(DatabaseAccessError::Write(_))));
+        assert_eq!(count_temperatures(&db), 0);
+    }
+
+    fn load_rollups(db: &Database, resolution: Resolution) -> Vec<(u64, f32, f32, f32, u64)> {
+        db.read(|connection| {
+            let mut statement = connection
+                .prepare(&format!(
+                    "select date, value_min, value_avg, value_max, samples from {} order by date",
+                    resolution.table()
+                ))
+                .map_err(DatabaseAccessError::Read)?;
+            let rollups = statement
+                .query_map([], |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                })
+                .map_err(DatabaseAccessError::Read)?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(DatabaseAccessError::Read)?;
+            Ok(rollups)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn update_rollups_aggregates_buckets_and_recomputes_the_youngest() {
+        let (_dir, db) = open_temp_database();
+        let save = |date: u64, value: f32| {
+            let temperatures = vec![Temperature::new(String::from("flow"), value)];
+            db.save_temperatures(TemperaturesByTime::new(date, temperatures))
+                .unwrap();
+        };
+        let minute = 60_000;
+
+        save(0, 40.0);
+        save(2 * minute, 50.0);
+        save(5 * minute, 60.0);
+        db.update_rollups().unwrap();
+
+        assert_eq!(
+            load_rollups(&db, Resolution::FiveMinutes),
+            vec![(0, 40.0, 45.0, 50.0, 2), (5 * minute, 60.0, 60.0, 60.0, 1)]
+        );
+        assert_eq!(
+            load_rollups(&db, Resolution::Hourly),
+            vec![(0, 40.0, 50.0, 60.0, 3)]
+        );
+
+        // a reading later in the youngest bucket updates it instead of adding one
+        save(7 * minute, 70.0);
+        db.update_rollups().unwrap();
+
+        assert_eq!(
+            load_rollups(&db, Resolution::FiveMinutes),
+            vec![(0, 40.0, 45.0, 50.0, 2), (5 * minute, 60.0, 65.0, 70.0, 2)]
+        );
+        assert_eq!(
+            load_rollups(&db, Resolution::Hourly),
+            vec![(0, 40.0, 55.0, 70.0, 4)]
+        );
+    }
+
+    #[test]
+    fn reads_do_not_wait_for_a_write_in_progress() {
+        let (_dir, db) = open_temp_database();
+        let db = Arc::new(db);
+        let (sender, receiver) = mpsc::channel();
+
+        db.write(|connection| {
+            let transaction = connection
+                .transaction()
+                .map_err(DatabaseAccessError::Write)?;
+            transaction
+                .execute(
+                    "insert into temperatures (date, name, value) values (1000, 'flow', 40.0)",
+                    (),
+                )
+                .map_err(DatabaseAccessError::Write)?;
+
+            let reader = db.clone();
+            thread::spawn(move || sender.send(count_temperatures(&reader)).unwrap());
+            // the uncommitted row is not visible yet
+            let count = receiver.recv_timeout(Duration::from_secs(5));
+            assert_eq!(count, Ok(0));
+
+            transaction.commit().map_err(DatabaseAccessError::Write)
+        })
+        .unwrap();
+
+        assert_eq!(count_temperatures(&db), 1);
+    }
+
+    #[test]
+    fn stats_count_reads_writes_and_waits_for_the_writer() {
+        let (_dir, db) = open_temp_database();
+
+        count_temperatures(&db);
+        count_temperatures(&db);
+        // a read within a read needs a second connection
+        db.read(|_| Ok(count_temperatures(&db))).unwrap();
+
+        thread::scope(|scope| {
+            db.write(|_| {
+                scope.spawn(|| db.write(|_| Ok(())).unwrap());
+                thread::sleep(Duration::from_millis(50));
+                Ok(())
             })
-            .map_err(DatabaseAccessError::Read)?;
+            .unwrap();
+        });
 
-        if let Some(max_date) = date_iter.next() {
-            let max_date = max_date.map_err(DatabaseAccessError::Read)?;
-            return Ok(Some(max_date));
-        } else {
-            return Ok(None);
+        let stats = db.stats();
+        assert_eq!(stats.reads, 4);
+        assert_eq!(stats.read_connections_opened, 2);
+        assert_eq!(stats.writes, 2);
+        assert!(stats.write_wait_micros_max >= 40_000);
+        assert!(stats.write_wait_micros_total >= stats.write_wait_micros_max);
+    }
+
+    #[test]
+    fn load_range_stats_aggregates_per_sensor() {
+        let (_dir, db) = open_temp_database();
+        let now = now_millis().unwrap();
+
+        for (date, flow) in [(now - 2000, 40.0), (now - 1000, 50.0)] {
+            let temperatures = vec![
+                Temperature::new(String::from("flow"), flow),
+                Temperature::new(String::from("return"), 30.0),
+            ];
+            db.save_temperatures(TemperaturesByTime::new(date, temperatures))
+                .unwrap();
         }
+
+        let stats = db
+            .load_range_stats(now - 5000, now, None, Some(45.0))
+            .unwrap();
+
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].name, "flow");
+        assert_eq!(
+            (stats[0].min, stats[0].max, stats[0].mean),
+            (40.0, 50.0, 45.0)
+        );
+        assert_eq!(stats[0].covered_seconds, 30);
+        assert_eq!(stats[0].above_seconds, 15);
+
+        let stats = db
+            .load_range_stats(now - 5000, now, Some("return"), None)
+            .unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].above_seconds, 0);
     }
 
-    pub fn save_temperatures(
-        &self,
-        temperatures_by_time: TemperaturesByTime,
-    ) -> Result<(), DatabaseAccessError> {
-        let saving_result: Result<Vec<usize>, DatabaseAccessError> = temperatures_by_time
-            .temperatures()
+    #[test]
+    fn load_temperatures_between_stops_before_to() {
+        let (_dir, db) = open_temp_database();
+        let now = now_millis().unwrap();
+        for date in [now - 3000, now - 2000, now - 1000] {
+            let temperatures = vec![Temperature::new(String::from("flow"), 40.0)];
+            db.save_temperatures(TemperaturesByTime::new(date, temperatures))
+                .unwrap();
+        }
+
+        let dates: Vec<u64> = db
+            .load_temperatures_between(now - 3000, now - 1000)
+            .unwrap()
             .iter()
-            .map(|temperature| {
-                self.connection
-                    .execute(
-                        "insert into temperatures (date, name, value) values (?1, ?2, ?3)",
-                        (
-                            temperatures_by_time.date(),
-                            temperature.name(),
-                            temperature.value_rounded_as_string(),
-                        ),
-                    )
-                    .map_err(DatabaseAccessError::Write)
-            })
+            .map(|temperatures| temperatures.date())
             .collect();
+        assert_eq!(dates, vec![now - 3000, now - 2000]);
+        assert_eq!(db.load_temperatures_since(now - 3000).unwrap().len(), 3);
+    }
 
-        match saving_result {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err),
-        }
+    #[test]
+    fn load_range_stats_covers_the_interval_of_each_reading() {
+        let (_dir, db) = open_temp_database();
+        let now = now_millis().unwrap();
+        let flow = |date: u64| {
+            TemperaturesByTime::new(date, vec![Temperature::new(String::from("flow"), 40.0)])
+        };
+
+        db.save_temperatures(flow(now - 1000)).unwrap();
+        let config = db.load_recorder_config().unwrap();
+        db.save_recorder_config(RecorderConfig {
+            interval_seconds: 60,
+            ..config
+        })
+        .unwrap();
+        db.save_temperatures(flow(now + 1000)).unwrap();
+
+        let stats = db
+            .load_range_stats(now - 5000, now + 5000, None, Some(30.0))
+            .unwrap();
+        assert_eq!(stats[0].covered_seconds, 15 + 60);
+        assert_eq!(stats[0].above_seconds, 15 + 60);
     }
 
-    pub fn delete_old_temperatures(&self) -> Result<usize, DatabaseAccessError> {
-        let config = self.load_recorder_config()?;
-        let keep_days = config.keep_days;
+    #[test]
+    fn save_recorder_config_keeps_interval_history() {
+        let (_dir, db) = open_temp_database();
+        let config = db.load_recorder_config().unwrap();
 
-        self.connection
-            .execute(
-                "delete from temperatures where date < (strftime('%s', 'now') - ?1 * 86400) * 1000",
-                [keep_days],
-            )
-            .map_err(DatabaseAccessError::Delete)
+        db.save_recorder_config(config.clone()).unwrap();
+        db.save_recorder_config(RecorderConfig {
+            interval_seconds: 60,
+            ..config
+        })
+        .unwrap();
+
+        let history = db.load_interval_history().unwrap();
+        let intervals: Vec<u32> = history.iter().map(|c| c.interval_seconds).collect();
+        assert_eq!(intervals, vec![15, 60]);
+        assert_eq!(history[0].since, 0);
+    }
+
+    #[test]
+    fn audit_entries_keep_their_details() {
+        let (_dir, db) = open_temp_database();
+        let entry = AuditEntry::new(
+            1000,
+            AuditAction::SensorDeleted,
+            Some(String::from("192.168.1.5")),
+            serde_json::json!({ "name": "flow" }),
+        )
+        .with_token(Some(String::from("admin")));
+
+        db.save_audit_entry(&entry).unwrap();
+
+        assert_eq!(db.load_audit_entries(0, 2000).unwrap(), vec![entry]);
+        assert!(db.load_audit_entries(2000, 3000).unwrap().is_empty());
+    }
+
+    #[test]
+    fn trim_outbox_keeps_the_newest_batches() {
+        let (_dir, db) = open_temp_database();
+        for body in ["a", "b", "c"] {
+            db.push_outbox(body).unwrap();
+        }
+
+        assert_eq!(db.trim_outbox(2).unwrap(), 1);
+        assert_eq!(db.trim_outbox(2).unwrap(), 0);
+
+        let bodies: Vec<String> = db
+            .load_outbox(10)
+            .unwrap()
+            .into_iter()
+            .map(|batch| batch.body)
+            .collect();
+        assert_eq!(bodies, vec!["b", "c"]);
     }
 }
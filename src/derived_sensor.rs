@@ -0,0 +1,395 @@
+use crate::sensor_config::SensorConfig;
+use crate::temperature_recorder::{round, Temperature};
+
+/// Arithmetic over the values of other sensors, e.g. `flow - return` or
+/// `avg(tank_top, tank_middle, tank_bottom)`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(f32),
+    Sensor(String),
+    Negate(Box<Expression>),
+    Binary(Box<Expression>, Operator, Box<Expression>),
+    Function(Function, Vec<Expression>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Avg,
+    Min,
+    Max,
+    Abs,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f32),
+    Name(String),
+    Symbol(char),
+}
+
+impl Expression {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let tokens = tokenize(expression)?;
+        let mut parser = Parser { tokens, next: 0 };
+        let parsed = parser.sum()?;
+
+        match parser.tokens.get(parser.next) {
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+            None => Ok(parsed),
+        }
+    }
+
+    /// Names of the sensors the expression refers to.
+    pub fn sensors(&self) -> Vec<&str> {
+        match self {
+            Expression::Number(_) => vec![],
+            Expression::Sensor(name) => vec![name],
+            Expression::Negate(operand) => operand.sensors(),
+            Expression::Binary(left, _, right) => {
+                let mut sensors = left.sensors();
+                sensors.extend(right.sensors());
+                sensors
+            }
+            Expression::Function(_, arguments) => {
+                arguments.iter().flat_map(|a| a.sensors()).collect()
+            }
+        }
+    }
+
+    /// Value of the expression, `None` if a sensor has no value or the
+    /// result is not a number, e.g. after dividing by zero.
+    pub fn evaluate<F>(&self, value: &F) -> Option<f32>
+    where
+        F: Fn(&str) -> Option<f32>,
+    {
+        let result = match self {
+            Expression::Number(number) => *number,
+            Expression::Sensor(name) => value(name)?,
+            Expression::Negate(operand) => -operand.evaluate(value)?,
+            Expression::Binary(left, operator, right) => {
+                let (left, right) = (left.evaluate(value)?, right.evaluate(value)?);
+                match operator {
+                    Operator::Add => left + right,
+                    Operator::Subtract => left - right,
+                    Operator::Multiply => left * right,
+                    Operator::Divide => left / right,
+                }
+            }
+            Expression::Function(function, arguments) => {
+                let values = arguments
+                    .iter()
+                    .map(|argument| argument.evaluate(value))
+                    .collect::<Option<Vec<f32>>>()?;
+                match function {
+                    Function::Avg => values.iter().sum::<f32>() / values.len() as f32,
+                    Function::Min => values.into_iter().fold(f32::INFINITY, f32::min),
+                    Function::Max => values.into_iter().fold(f32::NEG_INFINITY, f32::max),
+                    Function::Abs => values[0].abs(),
+                }
+            }
+        };
+
+        Some(result).filter(|result| result.is_finite())
+    }
+}
+
+impl Function {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "avg" => Some(Function::Avg),
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "abs" => Some(Function::Abs),
+            _ => None,
+        }
+    }
+}
+
+/// Values of the derived sensors computed from the `temperatures` read, in
+/// the order they are configured, so derived sensors may refer to earlier
+/// ones.
+///
+/// Each value is only kept, and seen by the sensors after it, if `accept`
+/// returns true for it.
+pub fn derive<F>(
+    sensor_config: &SensorConfig,
+    temperatures: &[Temperature],
+    mut accept: F,
+) -> Vec<Temperature>
+where
+    F: FnMut(&Temperature) -> bool,
+{
+    let mut derived: Vec<Temperature> = vec![];
+
+    for sensor in sensor_config.sensors() {
+        let expression = match sensor.parsed_expression() {
+            Some(expression) => expression,
+            None => continue,
+        };
+
+        let value = expression.evaluate(&|name| {
+            temperatures
+                .iter()
+                .chain(derived.iter())
+                .find(|temperature| temperature.name() == name)
+                .map(|temperature| temperature.value())
+        });
+
+        match value {
+            Some(value) => {
+                let value = value + sensor.offset();
+                let value = match sensor.precision() {
+                    Some(precision) => round(value, precision),
+                    None => value,
+                };
+                let temperature = Temperature::new(sensor.name().to_owned(), value);
+                if accept(&temperature) {
+                    derived.push(temperature);
+                }
+            }
+            None => log::warn!("No value for derived sensor {}", sensor.name()),
+        }
+    }
+
+    derived
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = expression.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                number.push(c);
+                chars.next();
+            }
+            let number = number
+                .parse()
+                .map_err(|_| format!("Invalid number {}", number))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                name.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Name(name));
+        } else if "+-*/(),".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else {
+            return Err(format!("Unexpected character {}", c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent parser, multiplication and division binding stronger
+/// than addition and subtraction.
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        match self.advance() {
+            Some(Token::Symbol(c)) if c == symbol => Ok(()),
+            Some(token) => Err(format!("Expected {} but found {:?}", symbol, token)),
+            None => Err(format!("Expected {} at the end", symbol)),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expression, String> {
+        let mut expression = self.product()?;
+
+        while let Some(Token::Symbol(c @ ('+' | '-'))) = self.peek().cloned() {
+            self.next += 1;
+            let operator = if c == '+' {
+                Operator::Add
+            } else {
+                Operator::Subtract
+            };
+            expression =
+                Expression::Binary(Box::new(expression), operator, Box::new(self.product()?));
+        }
+
+        Ok(expression)
+    }
+
+    fn product(&mut self) -> Result<Expression, String> {
+        let mut expression = self.unary()?;
+
+        while let Some(Token::Symbol(c @ ('*' | '/'))) = self.peek().cloned() {
+            self.next += 1;
+            let operator = if c == '*' {
+                Operator::Multiply
+            } else {
+                Operator::Divide
+            };
+            expression =
+                Expression::Binary(Box::new(expression), operator, Box::new(self.unary()?));
+        }
+
+        Ok(expression)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        if self.peek() == Some(&Token::Symbol('-')) {
+            self.next += 1;
+            return Ok(Expression::Negate(Box::new(self.unary()?)));
+        }
+
+        self.operand()
+    }
+
+    fn operand(&mut self) -> Result<Expression, String> {
+        match self.advance() {
+            Some(Token::Number(number)) => Ok(Expression::Number(number)),
+            Some(Token::Symbol('(')) => {
+                let expression = self.sum()?;
+                self.expect(')')?;
+                Ok(expression)
+            }
+            Some(Token::Name(name)) if self.peek() == Some(&Token::Symbol('(')) => {
+                let function =
+                    Function::parse(&name).ok_or_else(|| format!("Unknown function {}", name))?;
+                self.next += 1;
+
+                let mut arguments = vec![self.sum()?];
+                while self.peek() == Some(&Token::Symbol(',')) {
+                    self.next += 1;
+                    arguments.push(self.sum()?);
+                }
+                self.expect(')')?;
+
+                if function == Function::Abs && arguments.len() != 1 {
+                    return Err(String::from("abs takes exactly one argument"));
+                }
+
+                Ok(Expression::Function(function, arguments))
+            }
+            Some(Token::Name(name)) => Ok(Expression::Sensor(name)),
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+            None => Err(String::from("Unexpected end of expression")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(expression: &str) -> Option<f32> {
+        let values = |name: &str| match name {
+            "flow" => Some(60.0),
+            "return" => Some(45.0),
+            "tank_top" => Some(55.0),
+            _ => None,
+        };
+        Expression::parse(expression).unwrap().evaluate(&values)
+    }
+
+    #[test]
+    fn evaluates_expressions() {
+        assert_eq!(evaluate("flow - return"), Some(15.0));
+        assert_eq!(evaluate("flow - return * 2"), Some(-30.0));
+        assert_eq!(evaluate("(flow - return) / 2"), Some(7.5));
+        assert_eq!(evaluate("-flow + 1.5"), Some(-58.5));
+        assert_eq!(evaluate("avg(flow, return, tank_top)"), Some(160.0 / 3.0));
+        assert_eq!(
+            evaluate("max(flow, tank_top) - min(return, 50)"),
+            Some(15.0)
+        );
+        assert_eq!(evaluate("abs(return - flow)"), Some(15.0));
+        assert_eq!(evaluate("flow / (return - 45)"), None);
+        assert_eq!(evaluate("flow - outdoor"), None);
+    }
+
+    #[test]
+    fn derives_values_in_configured_order() {
+        let config = SensorConfig::parse(
+            r#"
+            [[sensors]]
+            name = "flow"
+            kind = "mqtt"
+            topic = "boiler/flow"
+
+            [[sensors]]
+            name = "return"
+            kind = "mqtt"
+            topic = "boiler/return"
+
+            [[sensors]]
+            name = "spread"
+            kind = "derived"
+            expression = "flow - return"
+
+            [[sensors]]
+            name = "half_spread"
+            kind = "derived"
+            expression = "spread / 2"
+        "#,
+        )
+        .unwrap();
+        let read = vec![
+            Temperature::new(String::from("flow"), 60.0),
+            Temperature::new(String::from("return"), 44.5),
+        ];
+
+        let derived: Vec<(String, f32)> = derive(&config, &read, |_| true)
+            .into_iter()
+            .map(|temperature| (temperature.name(), temperature.value()))
+            .collect();
+
+        assert_eq!(
+            derived,
+            vec![
+                (String::from("spread"), 15.5),
+                (String::from("half_spread"), 7.75)
+            ]
+        );
+        // without the return temperature, e.g. after it was rejected
+        assert!(derive(&config, &read[..1], |_| true).is_empty());
+
+        // sensors after a rejected one don't see its value
+        let derived = derive(&config, &read, |temperature| temperature.name() != "spread");
+        assert!(derived.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!(Expression::parse("flow -").is_err());
+        assert!(Expression::parse("(flow - return").is_err());
+        assert!(Expression::parse("median(flow, return)").is_err());
+        assert!(Expression::parse("flow % 2").is_err());
+        assert!(Expression::parse("flow return").is_err());
+        assert_eq!(
+            Expression::parse("flow - return").unwrap().sensors(),
+            vec!["flow", "return"]
+        );
+    }
+}
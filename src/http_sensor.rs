@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Responses larger than this are not a temperature reading
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug)]
+pub enum HttpSensorError {
+    Request(Box<ureq::Error>),
+    Body(io::Error),
+}
+
+/// GETs `url` and returns the response body.
+pub fn fetch(url: &str, timeout: Duration) -> Result<String, HttpSensorError> {
+    let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+    let response = agent
+        .get(url)
+        .call()
+        .map_err(|e| HttpSensorError::Request(Box::new(e)))?;
+
+    let mut body = String::new();
+    response
+        .into_reader()
+        .take(MAX_BODY_BYTES)
+        .read_to_string(&mut body)
+        .map_err(HttpSensorError::Body)?;
+
+    Ok(body)
+}
+
+/// Last successfully read value of each remote sensor, used while the
+/// remote end is briefly unreachable.
+#[derive(Default)]
+pub struct LastValueCache {
+    values: Mutex<HashMap<String, (Instant, f32)>>,
+}
+
+impl LastValueCache {
+    pub fn store(&self, name: &str, value: f32) {
+        if let Ok(mut values) = self.values.lock() {
+            values.insert(name.to_owned(), (Instant::now(), value));
+        }
+    }
+
+    /// Returns the last value of `name` if it is not older than `max_age`.
+    pub fn recent(&self, name: &str, max_age: Duration) -> Option<f32> {
+        let values = self.values.lock().ok()?;
+        values
+            .get(name)
+            .filter(|(stored, _)| stored.elapsed() <= max_age)
+            .map(|(_, value)| *value)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serves `body` to `requests` HTTP requests and returns the base URL.
+    pub fn serve(body: &'static str, requests: usize) -> String {
+        serve_status("200 OK", body, requests)
+    }
+
+    /// Like [`serve`], answering with `status`, e.g. `400 Bad Request`.
+    pub fn serve_status(status: &'static str, body: &'static str, requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        format!("http://{}", address)
+    }
+
+    #[test]
+    fn fetches_response_body() {
+        let url = serve(r#"{"temperature": 21.5}"#, 1);
+
+        let body = fetch(&url, Duration::from_secs(5)).unwrap();
+        assert_eq!(body, r#"{"temperature": 21.5}"#);
+    }
+
+    #[test]
+    fn returns_only_recent_cached_values() {
+        let cache = LastValueCache::default();
+        cache.store("esp32", 21.5);
+
+        assert_eq!(cache.recent("esp32", Duration::from_secs(60)), Some(21.5));
+        assert_eq!(cache.recent("esp32", Duration::ZERO), None);
+        assert_eq!(cache.recent("unknown", Duration::from_secs(60)), None);
+    }
+}
@@ -0,0 +1,304 @@
+use crate::app_config::InfluxConfig;
+use crate::recorder_scheduler::TemperatureSink;
+use crate::sensor_config::SensorConfig;
+use crate::storage::Storage;
+use crate::temperature_recorder::TemperaturesByTime;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Queued batches sent in one request while catching up, so a long outage
+/// is replayed in several requests instead of one huge one
+const BATCHES_PER_REQUEST: usize = 500;
+
+/// Wait before looking for new batches once the outbox is sent or while
+/// InfluxDB is not reachable
+const REPLAY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum InfluxForwarderError {
+    Request(Box<ureq::Error>),
+}
+
+/// Forwards every reading to InfluxDB in line protocol.
+///
+/// Batches are queued in the outbox of the storage on the tick and sent by a
+/// thread of their own, which only removes them once written, so readings
+/// taken while the network is down are sent in order as soon as it is back.
+/// Batches InfluxDB rejects are dropped, as sending them again would block
+/// the ones queued after them.
+#[derive(Clone)]
+pub struct InfluxForwarder {
+    config: InfluxConfig,
+    db: Arc<dyn Storage>,
+    agent: ureq::Agent,
+}
+
+impl InfluxForwarder {
+    pub fn new(config: InfluxConfig, db: Arc<dyn Storage>) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build();
+
+        Self { config, db, agent }
+    }
+
+    /// Creates the forwarder and starts the thread replaying its outbox.
+    pub fn spawn(config: InfluxConfig, db: Arc<dyn Storage>) -> Self {
+        let forwarder = Self::new(config, db);
+
+        let replayer = forwarder.clone();
+        thread::spawn(move || loop {
+            if !replayer.replay() {
+                thread::sleep(REPLAY_DELAY);
+            }
+        });
+
+        forwarder
+    }
+
+    fn write(&self, body: &str) -> Result<(), InfluxForwarderError> {
+        let mut request = self
+            .agent
+            .post(&self.config.url)
+            .set("Content-Type", "text/plain; charset=utf-8");
+
+        if let Some(token) = &self.config.token {
+            request = request.set("Authorization", &format!("Token {}", token));
+        }
+
+        request
+            .send_string(body)
+            .map_err(|e| InfluxForwarderError::Request(Box::new(e)))?;
+
+        Ok(())
+    }
+
+    /// Sends the oldest queued batches. They are kept if InfluxDB is not
+    /// reachable or fails, and dropped if it rejects them.
+    ///
+    /// Returns whether more batches may be waiting, so they are sent right
+    /// away.
+    fn replay(&self) -> bool {
+        let batches = match self.db.load_outbox(BATCHES_PER_REQUEST) {
+            Ok(batches) => batches,
+            Err(error) => {
+                log::error!("Error loading InfluxDB outbox {:?}", error);
+                return false;
+            }
+        };
+
+        let last_id = match batches.last() {
+            Some(batch) => batch.id,
+            None => return false,
+        };
+
+        let body = batches
+            .iter()
+            .map(|batch| batch.body.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        match self.write(&body) {
+            Ok(()) => {}
+            Err(InfluxForwarderError::Request(error)) if is_rejected(&error) => {
+                log::error!(
+                    "InfluxDB rejected {} batches, dropping them {:?}",
+                    batches.len(),
+                    error
+                );
+            }
+            Err(error) => {
+                log::warn!("Error writing to InfluxDB, keeping batches {:?}", error);
+                return false;
+            }
+        }
+
+        if let Err(error) = self.db.delete_outbox(last_id) {
+            log::error!("Error deleting forwarded batches from outbox {:?}", error);
+            return false;
+        }
+
+        batches.len() == BATCHES_PER_REQUEST
+    }
+}
+
+impl TemperatureSink for InfluxForwarder {
+    fn send(&mut self, _sensor_config: &SensorConfig, temperatures: &TemperaturesByTime) {
+        let body = line_protocol(&self.config, temperatures);
+
+        if !body.is_empty() {
+            if let Err(error) = self.db.push_outbox(&body) {
+                log::error!("Error queueing temperatures for InfluxDB {:?}", error);
+            }
+
+            match self.db.trim_outbox(self.config.max_queued_batches) {
+                Ok(0) => {}
+                Ok(dropped) => log::warn!("Dropped {} batches queued for InfluxDB", dropped),
+                Err(error) => log::error!("Error trimming InfluxDB outbox {:?}", error),
+            }
+        }
+    }
+}
+
+/// Whether InfluxDB refused the request itself, e.g. because of a malformed
+/// point or a missing permission, so it would refuse it again.
+fn is_rejected(error: &ureq::Error) -> bool {
+    matches!(error, ureq::Error::Status(400..=499, _))
+}
+
+/// One point per temperature, tagged with the sensor name, with a
+/// timestamp in nanoseconds, the default precision of the write endpoints.
+fn line_protocol(config: &InfluxConfig, temperatures: &TemperaturesByTime) -> String {
+    let timestamp = temperatures.date() as u128 * 1_000_000;
+
+    temperatures
+        .temperatures()
+        .iter()
+        .map(|temperature| {
+            let mut tags = config.tags.clone();
+            tags.insert(String::from("sensor"), temperature.name());
+
+            let tags: String = tags
+                .iter()
+                .map(|(key, value)| format!(",{}={}", escape_tag(key), escape_tag(value)))
+                .collect();
+
+            format!(
+                "{}{} value={} {}",
+                escape_measurement(&config.measurement),
+                tags,
+                temperature.value(),
+                timestamp
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn escape_measurement(measurement: &str) -> String {
+    measurement.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+fn escape_tag(tag: &str) -> String {
+    tag.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_sensor::tests::{serve, serve_status};
+    use crate::memory_storage::MemoryStorage;
+    use crate::temperature_recorder::Temperature;
+    use std::net::TcpListener;
+
+    fn config(url: &str) -> InfluxConfig {
+        toml::from_str(&format!(
+            r#"
+            url = "{}"
+            timeout_ms = 1000
+            tags = {{ house = "Haus am See" }}
+        "#,
+            url
+        ))
+        .unwrap()
+    }
+
+    fn temperatures(date: u64) -> TemperaturesByTime {
+        TemperaturesByTime::new(
+            date,
+            vec![
+                Temperature::new(String::from("tank top"), 61.5),
+                Temperature::new(String::from("flow"), 45.0),
+            ],
+        )
+    }
+
+    #[test]
+    fn converts_temperatures_to_line_protocol() {
+        let lines = line_protocol(
+            &config("http://localhost"),
+            &temperatures(1_700_000_000_000),
+        );
+
+        assert_eq!(
+            lines,
+            "temperature,house=Haus\\ am\\ See,sensor=tank\\ top value=61.5 1700000000000000000\n\
+            temperature,house=Haus\\ am\\ See,sensor=flow value=45 1700000000000000000"
+        );
+        assert_eq!(escape_tag("C:\\boiler, a=b"), "C:\\\\boiler\\,\\ a\\=b");
+    }
+
+    #[test]
+    fn buffers_batches_until_influx_is_reachable() {
+        // a port nobody listens on anymore
+        let unreachable = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let sensor_config = SensorConfig::parse("sensors = []").unwrap();
+
+        let mut forwarder = InfluxForwarder::new(config(&unreachable), db.clone());
+        forwarder.send(&sensor_config, &temperatures(1_000));
+        assert!(!forwarder.replay());
+        forwarder.send(&sensor_config, &temperatures(2_000));
+        assert!(!forwarder.replay());
+
+        let queued = db.load_outbox(10).unwrap();
+        assert_eq!(queued.len(), 2);
+        assert!(queued[0].body.ends_with(" 1000000000"));
+
+        let mut forwarder = InfluxForwarder::new(config(&serve("", 1)), db.clone());
+        forwarder.send(&sensor_config, &temperatures(3_000));
+        assert_eq!(db.load_outbox(10).unwrap().len(), 3);
+        assert!(!forwarder.replay());
+
+        assert!(db.load_outbox(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn drops_rejected_batches_and_keeps_failed_ones() {
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let sensor_config = SensorConfig::parse("sensors = []").unwrap();
+
+        let failing = serve_status("503 Service Unavailable", "", 1);
+        let mut forwarder = InfluxForwarder::new(config(&failing), db.clone());
+        forwarder.send(&sensor_config, &temperatures(1_000));
+        forwarder.replay();
+        assert_eq!(db.load_outbox(10).unwrap().len(), 1);
+
+        let rejecting = serve_status("400 Bad Request", r#"{"code":"invalid"}"#, 1);
+        let mut forwarder = InfluxForwarder::new(config(&rejecting), db.clone());
+        forwarder.send(&sensor_config, &temperatures(2_000));
+        forwarder.replay();
+        assert!(db.load_outbox(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_only_the_newest_batches_while_unreachable() {
+        let unreachable = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let sensor_config = SensorConfig::parse("sensors = []").unwrap();
+        let config = InfluxConfig {
+            max_queued_batches: 2,
+            ..config(&unreachable)
+        };
+
+        let mut forwarder = InfluxForwarder::new(config, db.clone());
+        for date in [1_000, 2_000, 3_000] {
+            forwarder.send(&sensor_config, &temperatures(date));
+        }
+
+        let queued = db.load_outbox(10).unwrap();
+        assert_eq!(queued.len(), 2);
+        assert!(queued[0].body.ends_with(" 2000000000"));
+    }
+}
@@ -0,0 +1,145 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, SecondsFormat, TimeDelta, TimeZone};
+use chrono_tz::Tz;
+use rocket::form::{self, FromFormField, ValueField};
+use rocket::request::FromParam;
+
+/// A point in time given either as epoch milliseconds or as ISO-8601 string,
+/// e.g. `2024-03-01`, `2024-03-01T06:30` or `2024-03-01T06:30:00+01:00`.
+///
+/// Dates and times without offset are local to the configured timezone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DateParam {
+    Millis(u64),
+    Zoned(DateTime<FixedOffset>),
+    Local(NaiveDateTime),
+}
+
+const LOCAL_FORMATS: [&str; 3] = [
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M:%S",
+];
+
+impl DateParam {
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+
+        if let Ok(millis) = value.parse::<u64>() {
+            return Some(DateParam::Millis(millis));
+        }
+
+        // a `+` of the offset arrives as space when not encoded in a query
+        let zoned = DateTime::parse_from_rfc3339(value)
+            .or_else(|_| DateTime::parse_from_rfc3339(&value.replace(' ', "+")));
+        if let Ok(date) = zoned {
+            return Some(DateParam::Zoned(date));
+        }
+
+        LOCAL_FORMATS
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+            .or_else(|| {
+                NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                    .ok()
+                    .and_then(|date| date.and_hms_opt(0, 0, 0))
+            })
+            .map(DateParam::Local)
+    }
+
+    /// Epoch milliseconds, `None` for dates before 1970.
+    pub fn millis(&self, timezone: &Tz) -> Option<u64> {
+        match self {
+            DateParam::Millis(millis) => Some(*millis),
+            DateParam::Zoned(date) => u64::try_from(date.timestamp_millis()).ok(),
+            DateParam::Local(date) => local_millis(*date, timezone),
+        }
+    }
+}
+
+impl<'a> FromParam<'a> for DateParam {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        Self::parse(param).ok_or(param)
+    }
+}
+
+#[rocket::async_trait]
+impl<'v> FromFormField<'v> for DateParam {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        Self::parse(field.value).ok_or_else(|| {
+            form::Error::validation("expected epoch milliseconds or an ISO-8601 date").into()
+        })
+    }
+}
+
+/// Epoch milliseconds of a local time, moved past the gap when the clocks
+/// are put forward at that time.
+pub fn local_millis<Z: TimeZone>(date: NaiveDateTime, timezone: &Z) -> Option<u64> {
+    (0..3)
+        .find_map(|hours| {
+            timezone
+                .from_local_datetime(&(date + TimeDelta::hours(hours)))
+                .earliest()
+        })
+        .and_then(|date| u64::try_from(date.timestamp_millis()).ok())
+}
+
+/// Start of `date` in `timezone`, which is a bit later than midnight in the
+/// few timezones changing clocks at midnight.
+pub fn midnight<Z: TimeZone>(date: NaiveDate, timezone: &Z) -> u64 {
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|midnight| local_millis(midnight, timezone))
+        .unwrap_or_default()
+}
+
+/// ISO-8601 representation of epoch milliseconds in `timezone`.
+pub fn iso_date(millis: u64, timezone: &Tz) -> String {
+    timezone
+        .timestamp_millis_opt(millis as i64)
+        .earliest()
+        .map(|date| date.to_rfc3339_opts(SecondsFormat::Millis, false))
+        .unwrap_or_default()
+}
+
+/// Timezone of the system, UTC if it can't be determined.
+pub fn system_timezone() -> Tz {
+    iana_time_zone::get_timezone()
+        .ok()
+        .and_then(|name| name.parse().ok())
+        .unwrap_or(Tz::UTC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_epoch_millis_and_iso_dates() {
+        let berlin = Tz::Europe__Berlin;
+
+        let millis = |value: &str| DateParam::parse(value).and_then(|d| d.millis(&berlin));
+
+        assert_eq!(millis("1709274600000"), Some(1709274600000));
+        assert_eq!(millis("2024-03-01T06:30:00Z"), Some(1709274600000));
+        assert_eq!(millis("2024-03-01T07:30:00+01:00"), Some(1709274600000));
+        assert_eq!(millis("2024-03-01T07:30:00 01:00"), Some(1709274600000));
+        assert_eq!(millis("2024-03-01T07:30"), Some(1709274600000));
+        assert_eq!(millis("2024-03-01"), Some(1709247600000));
+        assert_eq!(millis("yesterday"), None);
+    }
+
+    #[test]
+    fn resolves_local_times_in_clock_change_gap() {
+        let berlin = Tz::Europe__Berlin;
+
+        // clocks went from 02:00 to 03:00 on that day
+        let date = DateParam::parse("2024-03-31T02:30").unwrap();
+        assert_eq!(date.millis(&berlin), Some(1711848600000));
+
+        assert_eq!(
+            iso_date(1711846800000, &berlin),
+            "2024-03-31T03:00:00.000+02:00"
+        );
+    }
+}
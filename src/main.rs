@@ -1,29 +1,76 @@
+pub mod app_config;
+pub mod audit;
+pub mod auth;
+pub mod burner_cycles;
+pub mod command_sensor;
+pub mod coverage;
+pub mod daily_stats;
 pub mod database;
+pub mod derived_sensor;
+pub mod http_sensor;
+pub mod influx_forwarder;
+pub mod local_time;
+pub mod memory_storage;
+pub mod modbus_sensor;
+pub mod mqtt_publisher;
+pub mod mqtt_subscriber;
 pub mod recorder_scheduler;
+pub mod sample_filter;
+pub mod sensor_config;
+pub mod storage;
+pub mod sysfs_sensor;
+pub mod tank_energy;
 pub mod temperature_reader;
 pub mod temperature_recorder;
 
-use filesize::PathExt;
+use chrono_tz::Tz;
 use rocket::serde::json::Json;
-use rocket::State;
-use rocket_cors::CorsOptions;
+use rocket::{Build, Rocket, State};
+use rocket_cors::Cors;
 use serde::Serialize;
 use std::env;
-use std::path::Path;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
-use crate::database::{Database, DatabaseAccessError, DatabaseInitError};
-use crate::recorder_scheduler::{RecorderScheduler, RecorderSchedulerError};
-use crate::temperature_reader::{SensorConfig, TemperatureReader};
-use crate::temperature_recorder::{RecorderConfig, TemperaturesByTime};
+use crate::app_config::{AppConfig, CorsConfigError};
+use crate::audit::{AuditAction, AuditEntry};
+use crate::auth::{AdminAccess, Authenticator, ReadAccess, Scope, TokenCommandError, TOKEN_USAGE};
+use crate::burner_cycles::{BurnerCycleDetector, CycleStats};
+use crate::coverage::{ExpectedInterval, SensorCoverage};
+use crate::daily_stats::DailyStats;
+use crate::database::{Database, DatabaseAccessError, DatabaseInitError, DatabaseStats};
+use crate::http_sensor::LastValueCache;
+use crate::influx_forwarder::InfluxForwarder;
+use crate::local_time::DateParam;
+use crate::memory_storage::MemoryStorage;
+use crate::mqtt_publisher::MqttPublisher;
+use crate::recorder_scheduler::RecorderScheduler;
+use crate::sample_filter::RejectedTemperature;
+use crate::sensor_config::{Sensor, SensorConfig, SensorConfigError, SensorConfigStore};
+use crate::storage::Storage;
+use crate::tank_energy::TankEnergyByTime;
+use crate::temperature_reader::TemperatureReader;
+use crate::temperature_recorder::{
+    now_millis, RecorderConfig, TemperaturesByTime, MAX_PRECISION, MILLIS_PER_DAY,
+};
 
 #[macro_use]
 extern crate rocket;
 
+/// Longest range of the endpoints taking `from` and `to`, several of which
+/// split it into days
+const MAX_RANGE_MILLIS: u64 = 366 * MILLIS_PER_DAY;
+
 #[derive(Responder)]
 enum ResponseError {
+    #[response(status = 401, content_type = "json")]
+    Unauthorized(String),
+    #[response(status = 403, content_type = "json")]
+    Forbidden(String),
     #[response(status = 404, content_type = "json")]
     NotFound(String),
+    #[response(status = 422, content_type = "json")]
+    Invalid(String),
     #[response(status = 500, content_type = "json")]
     Internal(String),
 }
@@ -32,81 +79,580 @@ enum ResponseError {
 // clear all temperatures
 // logs??
 
-#[get("/temperatures/last")]
+#[get("/temperatures/last?<precision>&<iso>")]
 fn get_last_temperatures(
+    precision: Option<u32>,
+    iso: Option<bool>,
+    _access: ReadAccess,
     state: &State<AppState>,
 ) -> Result<Json<TemperaturesByTime>, ResponseError> {
-    let db = state.db.lock().map_err(|err| {
-        log::warn!("Error retreiving database from state: {}", err);
-        ResponseError::Internal(String::from("Error retreiving database from state"))
-    })?;
-
-    let last_temperatures = db.load_last_temperature().map_err(|err| {
+    let precision = check_precision(precision)?;
+    let last_temperatures = state.db.load_last_temperature().map_err(|err| {
         log::warn!("Error accessing database: {:?}", err);
         ResponseError::Internal(String::from("Error accessing database"))
     })?;
 
-    return match last_temperatures {
-        Some(t) => Ok(Json::from(t)),
+    let sensor_config = state.sensors.current();
+
+    match last_temperatures {
+        Some(t) => {
+            let tank = sensor_config.tank().and_then(|tank| tank.energy(&t));
+            Ok(Json::from(present_temperatures(
+                t.with_tank(tank),
+                precision,
+                iso_timezone(iso, state),
+                &sensor_config,
+            )))
+        }
         None => Err(ResponseError::NotFound(String::from(
             "No temperatures found",
         ))),
-    };
+    }
 }
 
-#[get("/temperatures/since/<start_time>")]
+/// Temperatures since `start_time`, with `gaps` a missing reading with
+/// `null` value marks where readings of a sensor stopped.
+#[get("/temperatures/since/<start_time>?<precision>&<iso>&<gaps>")]
 fn get_temperatures_since(
-    start_time: u64,
+    start_time: DateParam,
+    precision: Option<u32>,
+    iso: Option<bool>,
+    gaps: Option<bool>,
+    _access: ReadAccess,
     state: &State<AppState>,
 ) -> Result<Json<Vec<TemperaturesByTime>>, ResponseError> {
-    let db = state.db.lock().map_err(|err| {
-        log::error!("Error retreiving database from state: {}", err);
-        ResponseError::Internal(String::from("Error retreiving database from state"))
+    let precision = check_precision(precision)?;
+    let start_time = resolve_date(start_time, state)?;
+    let mut temperatures = state
+        .db
+        .load_temperatures_since(start_time)
+        .map_err(|err| {
+            log::error!("Error accessing database: {:?}", err);
+            ResponseError::Internal(String::from("Error accessing database"))
+        })?;
+
+    if gaps.unwrap_or(false) {
+        let now = now_millis().map_err(|err| {
+            log::error!("Error reading time: {:?}", err);
+            ResponseError::Internal(String::from("Error reading time"))
+        })?;
+        let expected = ExpectedInterval::load(state.db.as_ref(), start_time).map_err(|err| {
+            log::error!("Error accessing database: {:?}", err);
+            ResponseError::Internal(String::from("Error accessing database"))
+        })?;
+        temperatures = coverage::with_gap_markers(temperatures, start_time, now, &expected);
+    }
+
+    let sensor_config = state.sensors.current();
+    let temperatures = temperatures
+        .into_iter()
+        .map(|t| present_temperatures(t, precision, iso_timezone(iso, state), &sensor_config))
+        .collect::<Vec<_>>();
+
+    Ok(Json::from(temperatures))
+}
+
+#[get("/tank/energy/since/<start_time>?<precision>&<iso>")]
+fn get_tank_energy_since(
+    start_time: DateParam,
+    precision: Option<u32>,
+    iso: Option<bool>,
+    _access: ReadAccess,
+    state: &State<AppState>,
+) -> Result<Json<Vec<TankEnergyByTime>>, ResponseError> {
+    let precision = check_precision(precision)?;
+    let start_time = resolve_date(start_time, state)?;
+    let sensor_config = state.sensors.current();
+    let tank = sensor_config
+        .tank()
+        .ok_or_else(|| ResponseError::NotFound(String::from("No tank configured")))?;
+
+    let temperatures = state
+        .db
+        .load_temperatures_since(start_time)
+        .map_err(|err| {
+            log::error!("Error accessing database: {:?}", err);
+            ResponseError::Internal(String::from("Error accessing database"))
+        })?;
+
+    let energy = tank
+        .energy_series(&temperatures)
+        .into_iter()
+        .map(|energy| match precision {
+            Some(precision) => energy.rounded(precision),
+            None => energy,
+        })
+        .map(|energy| match iso_timezone(iso, state) {
+            Some(timezone) => energy.with_iso_date(timezone),
+            None => energy,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Json::from(energy))
+}
+
+/// Burner cycles started between `from` and `to`, the last day by default.
+#[get("/analysis/cycles?<from>&<to>&<iso>")]
+fn get_burner_cycles(
+    from: Option<DateParam>,
+    to: Option<DateParam>,
+    iso: Option<bool>,
+    _access: ReadAccess,
+    state: &State<AppState>,
+) -> Result<Json<CycleStats>, ResponseError> {
+    let (from, to) = resolve_range(from, to, MILLIS_PER_DAY, state)?;
+
+    let cycles = state.db.load_burner_cycles(from, to).map_err(|err| {
+        log::error!("Error accessing database: {:?}", err);
+        ResponseError::Internal(String::from("Error accessing database"))
     })?;
 
-    let temperatures = db.load_temperatures_since(start_time).map_err(|err| {
+    let stats = CycleStats::new(cycles, from, to, &state.timezone);
+
+    Ok(Json::from(match iso_timezone(iso, state) {
+        Some(timezone) => stats.with_iso_dates(timezone),
+        None => stats,
+    }))
+}
+
+/// Statistics per local calendar day between `from` and `to`, the last
+/// week by default.
+#[get("/stats/daily?<from>&<to>&<sensor>&<threshold>")]
+fn get_daily_stats(
+    from: Option<DateParam>,
+    to: Option<DateParam>,
+    sensor: Option<&str>,
+    threshold: Option<f32>,
+    _access: ReadAccess,
+    state: &State<AppState>,
+) -> Result<Json<Vec<DailyStats>>, ResponseError> {
+    let (from, to) = resolve_range(from, to, 7 * MILLIS_PER_DAY, state)?;
+
+    let stats = daily_stats::daily_stats(
+        state.db.as_ref(),
+        from,
+        to,
+        sensor,
+        threshold,
+        &state.timezone,
+    )
+    .map_err(|err| {
         log::error!("Error accessing database: {:?}", err);
         ResponseError::Internal(String::from("Error accessing database"))
     })?;
 
-    return Ok(Json::from(temperatures));
+    Ok(Json::from(stats))
+}
+
+/// Gaps and coverage per sensor between `from` and `to`, the last day by
+/// default.
+#[get("/coverage?<from>&<to>&<sensor>&<iso>")]
+fn get_coverage(
+    from: Option<DateParam>,
+    to: Option<DateParam>,
+    sensor: Option<&str>,
+    iso: Option<bool>,
+    _access: ReadAccess,
+    state: &State<AppState>,
+) -> Result<Json<Vec<SensorCoverage>>, ResponseError> {
+    let (from, to) = resolve_range(from, to, MILLIS_PER_DAY, state)?;
+
+    let coverage = coverage::coverage(
+        state.db.as_ref(),
+        &state.sensors.current(),
+        from,
+        to,
+        sensor,
+    )
+    .map_err(|err| {
+        log::error!("Error accessing database: {:?}", err);
+        ResponseError::Internal(String::from("Error accessing database"))
+    })?;
+
+    let coverage = match iso_timezone(iso, state) {
+        Some(timezone) => coverage
+            .into_iter()
+            .map(|coverage| coverage.with_iso_dates(timezone))
+            .collect(),
+        None => coverage,
+    };
+
+    Ok(Json::from(coverage))
+}
+
+#[get("/temperatures/rejected/since/<start_time>?<iso>")]
+fn get_rejected_temperatures_since(
+    start_time: DateParam,
+    iso: Option<bool>,
+    _access: ReadAccess,
+    state: &State<AppState>,
+) -> Result<Json<Vec<RejectedTemperature>>, ResponseError> {
+    let start_time = resolve_date(start_time, state)?;
+    let rejected = state
+        .db
+        .load_rejected_temperatures_since(start_time)
+        .map_err(|err| {
+            log::error!("Error accessing database: {:?}", err);
+            ResponseError::Internal(String::from("Error accessing database"))
+        })?;
+
+    let rejected = match iso_timezone(iso, state) {
+        Some(timezone) => rejected
+            .into_iter()
+            .map(|rejected| rejected.with_iso_date(timezone))
+            .collect(),
+        None => rejected,
+    };
+
+    Ok(Json::from(rejected))
+}
+
+/// Epoch milliseconds of a date parameter in the configured timezone.
+fn resolve_date(date: DateParam, state: &State<AppState>) -> Result<u64, ResponseError> {
+    let millis = date.millis(&state.timezone).ok_or_else(|| {
+        ResponseError::Invalid(String::from("Dates before 1970 are not supported"))
+    })?;
+
+    // dates are handled as signed milliseconds by chrono and SQLite
+    match i64::try_from(millis) {
+        Ok(_) => Ok(millis),
+        Err(_) => Err(ResponseError::Invalid(String::from(
+            "Dates this far in the future are not supported",
+        ))),
+    }
+}
+
+/// Range between `from` and `to`, ending now and spanning `default_millis`
+/// if not given. It may span at most `MAX_RANGE_MILLIS`.
+fn resolve_range(
+    from: Option<DateParam>,
+    to: Option<DateParam>,
+    default_millis: u64,
+    state: &State<AppState>,
+) -> Result<(u64, u64), ResponseError> {
+    let to = match to {
+        Some(to) => resolve_date(to, state)?,
+        None => now_millis().map_err(|err| {
+            log::error!("Error reading time: {:?}", err);
+            ResponseError::Internal(String::from("Error reading time"))
+        })?,
+    };
+    let from = match from {
+        Some(from) => resolve_date(from, state)?,
+        None => to.saturating_sub(default_millis),
+    };
+
+    if from > to {
+        return Err(ResponseError::Invalid(String::from(
+            "from must not be after to",
+        )));
+    }
+
+    if to - from > MAX_RANGE_MILLIS {
+        return Err(ResponseError::Invalid(format!(
+            "Range must not span more than {} days",
+            MAX_RANGE_MILLIS / MILLIS_PER_DAY
+        )));
+    }
+
+    Ok((from, to))
+}
+
+/// The requested precision, which rounding can only apply up to
+/// `MAX_PRECISION` decimal places.
+fn check_precision(precision: Option<u32>) -> Result<Option<u32>, ResponseError> {
+    match precision {
+        Some(precision) if precision > MAX_PRECISION => Err(ResponseError::Invalid(format!(
+            "precision must be at most {}",
+            MAX_PRECISION
+        ))),
+        precision => Ok(precision),
+    }
+}
+
+/// Timezone to add ISO-8601 dates in, if requested.
+fn iso_timezone(iso: Option<bool>, state: &State<AppState>) -> Option<&Tz> {
+    iso.unwrap_or(false).then_some(&state.timezone)
+}
+
+/// Rounds temperatures to the requested precision, links them to their
+/// sensors and adds the ISO-8601 date in `timezone`, if given.
+fn present_temperatures(
+    temperatures: TemperaturesByTime,
+    precision: Option<u32>,
+    timezone: Option<&Tz>,
+    sensor_config: &SensorConfig,
+) -> TemperaturesByTime {
+    let temperatures = temperatures.map_temperatures(|temperature| {
+        let sensor = sensor_config
+            .sensor(&temperature.name())
+            .map(|sensor| uri!(get_sensor(sensor.name())).to_string());
+        temperature.with_sensor(sensor)
+    });
+    let temperatures = match timezone {
+        Some(timezone) => temperatures.with_iso_date(timezone),
+        None => temperatures,
+    };
+
+    match precision {
+        Some(precision) => temperatures.rounded(precision),
+        None => temperatures,
+    }
 }
 
 #[derive(Serialize)]
 struct AppHealth {
-    sensor_config: SensorConfig,
-    database_size_bytes: u64,
+    sensor_config: Arc<SensorConfig>,
+    sensor_config_error: Option<String>,
+    database_size_bytes: Option<u64>,
+    database_stats: DatabaseStats,
 }
 
 #[get("/health")]
-fn get_app_health() -> Result<Json<AppHealth>, ResponseError> {
-    let sensor_config = TemperatureReader::read_config("Sensor.toml").map_err(|error| {
-        log::error!("Error reading sensor configuration file: {:?}", error);
-        ResponseError::Internal(String::from("Error reading sensor configuration file"))
-    })?;
+fn get_app_health(
+    _access: ReadAccess,
+    state: &State<AppState>,
+) -> Result<Json<AppHealth>, ResponseError> {
+    let health = AppHealth {
+        sensor_config: state.sensors.current(),
+        sensor_config_error: state.sensors.last_error(),
+        database_size_bytes: state.db.size_on_disk(),
+        database_stats: state.db.stats(),
+    };
+
+    Ok(Json::from(health))
+}
+
+fn sensor_config_error(error: SensorConfigError) -> ResponseError {
+    match error {
+        SensorConfigError::Parse(error) => ResponseError::Invalid(error.to_string()),
+        SensorConfigError::Invalid(message) => ResponseError::Invalid(message),
+        SensorConfigError::UnknownSensor(name) => {
+            ResponseError::NotFound(format!("No sensor named {}", name))
+        }
+        error => {
+            log::error!("Error accessing sensor configuration file: {:?}", error);
+            ResponseError::Internal(String::from("Error accessing sensor configuration file"))
+        }
+    }
+}
+
+/// Records a configuration change or destructive action, which must not
+/// fail the request it was made by.
+///
+/// The client is the peer of the connection rather than the address in
+/// `X-Real-IP`, which any client could set.
+fn audit<T: Serialize>(
+    state: &State<AppState>,
+    client: Option<SocketAddr>,
+    access: &AdminAccess,
+    action: AuditAction,
+    details: &T,
+) {
+    let details = serde_json::to_value(details).unwrap_or_else(|err| {
+        log::error!("Error serializing audit details: {:?}", err);
+        serde_json::Value::Null
+    });
+    let entry = now_millis().map(|date| {
+        AuditEntry::new(
+            date,
+            action,
+            client.map(|peer| peer.ip().to_string()),
+            details,
+        )
+        .with_token(Some(access.token().to_owned()))
+    });
 
-    let path = Path::new("boiler-watch.db");
-    let database_size_bytes = path.size_on_disk().map_err(|error| {
-        log::error!("Error getting database file size: {:?}", error);
-        ResponseError::Internal(String::from("Error getting database file size"))
+    match entry {
+        Ok(entry) => {
+            if let Err(err) = state.db.save_audit_entry(&entry) {
+                log::error!("Error saving audit entry {:?}: {:?}", entry, err);
+            }
+        }
+        Err(err) => log::error!("Error reading time: {:?}", err),
+    }
+}
+
+/// Configuration changes and destructive actions between `from` and `to`,
+/// the last 30 days by default.
+#[get("/audit?<from>&<to>&<iso>")]
+fn get_audit_log(
+    from: Option<DateParam>,
+    to: Option<DateParam>,
+    iso: Option<bool>,
+    _access: AdminAccess,
+    state: &State<AppState>,
+) -> Result<Json<Vec<AuditEntry>>, ResponseError> {
+    let (from, to) = resolve_range(from, to, 30 * MILLIS_PER_DAY, state)?;
+
+    let entries = state.db.load_audit_entries(from, to).map_err(|err| {
+        log::error!("Error accessing database: {:?}", err);
+        ResponseError::Internal(String::from("Error accessing database"))
     })?;
 
-    let health = AppHealth {
-        sensor_config,
-        database_size_bytes,
+    let entries = match iso_timezone(iso, state) {
+        Some(timezone) => entries
+            .into_iter()
+            .map(|entry| entry.with_iso_date(timezone))
+            .collect(),
+        None => entries,
     };
 
-    Ok(Json::from(health))
+    Ok(Json::from(entries))
 }
 
-#[get("/config")]
-fn get_config(state: &State<AppState>) -> Result<Json<RecorderConfig>, ResponseError> {
-    let db = state.db.lock().map_err(|err| {
-        log::error!("Error retreiving database from state: {}", err);
-        ResponseError::Internal(String::from("Error retreiving database from state"))
+#[post("/sensors/reload")]
+fn reload_sensors(
+    client: Option<SocketAddr>,
+    access: AdminAccess,
+    state: &State<AppState>,
+) -> Result<Json<Arc<SensorConfig>>, ResponseError> {
+    let sensor_config = state.sensors.reload().map_err(sensor_config_error)?;
+    audit(
+        state,
+        client,
+        &access,
+        AuditAction::SensorsReloaded,
+        &sensor_config,
+    );
+
+    Ok(Json::from(sensor_config))
+}
+
+#[get("/sensors")]
+fn get_sensors(_access: ReadAccess, state: &State<AppState>) -> Json<Vec<Sensor>> {
+    let mut sensors = state.sensors.current().sensors().to_vec();
+    // sensors without sort order keep their configured order after the others
+    sensors.sort_by_key(|sensor| sensor.metadata().sort_order().unwrap_or(i32::MAX));
+
+    Json::from(sensors)
+}
+
+#[get("/sensors/<name>")]
+fn get_sensor(
+    name: &str,
+    _access: ReadAccess,
+    state: &State<AppState>,
+) -> Result<Json<Sensor>, ResponseError> {
+    match state.sensors.current().sensor(name) {
+        Some(sensor) => Ok(Json::from(sensor.clone())),
+        None => Err(ResponseError::NotFound(format!("No sensor named {}", name))),
+    }
+}
+
+#[derive(Serialize)]
+struct SensorTestRead {
+    sensor: Sensor,
+    /// Not set for pushed and derived sensors, which can't be read on demand
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+}
+
+fn test_read_sensor(
+    sensor: Sensor,
+    sensor_config: Arc<SensorConfig>,
+) -> Result<SensorTestRead, ResponseError> {
+    if !sensor.kind().is_read() {
+        return Ok(SensorTestRead {
+            sensor,
+            temperature: None,
+        });
+    }
+
+    // a fresh cache, so remote sensors are really read
+    let reader = TemperatureReader::new(sensor_config, Arc::default());
+    let temperature = reader.read_calibrated(&sensor).map_err(|error| {
+        log::warn!("Test read of sensor failed: {:?}", error);
+        ResponseError::Invalid(format!("Error reading sensor: {:?}", error))
     })?;
 
-    let recorder_config = db.load_recorder_config().map_err(|err| {
+    Ok(SensorTestRead {
+        sensor,
+        temperature: Some(temperature),
+    })
+}
+
+#[post("/sensors", data = "<sensor>")]
+fn create_sensor(
+    sensor: Json<Sensor>,
+    client: Option<SocketAddr>,
+    access: AdminAccess,
+    state: &State<AppState>,
+) -> Result<Json<SensorTestRead>, ResponseError> {
+    let sensor = sensor.into_inner();
+    // validate before reading, so only sensors which could be saved are run
+    let candidate = state
+        .sensors
+        .check_add(&sensor)
+        .map_err(sensor_config_error)?;
+    let test_read = test_read_sensor(sensor, Arc::new(candidate))?;
+
+    state
+        .sensors
+        .add(test_read.sensor.clone())
+        .map_err(sensor_config_error)?;
+    audit(
+        state,
+        client,
+        &access,
+        AuditAction::SensorCreated,
+        &test_read.sensor,
+    );
+
+    Ok(Json::from(test_read))
+}
+
+#[put("/sensors/<name>", data = "<sensor>")]
+fn update_sensor(
+    name: &str,
+    sensor: Json<Sensor>,
+    client: Option<SocketAddr>,
+    access: AdminAccess,
+    state: &State<AppState>,
+) -> Result<Json<SensorTestRead>, ResponseError> {
+    let sensor = sensor.into_inner();
+    let candidate = state
+        .sensors
+        .check_update(name, &sensor)
+        .map_err(sensor_config_error)?;
+    let test_read = test_read_sensor(sensor, Arc::new(candidate))?;
+
+    let previous = state.sensors.current().sensor(name).cloned();
+    state
+        .sensors
+        .update(name, test_read.sensor.clone())
+        .map_err(sensor_config_error)?;
+    audit(
+        state,
+        client,
+        &access,
+        AuditAction::SensorUpdated,
+        &serde_json::json!({ "previous": previous, "sensor": test_read.sensor }),
+    );
+
+    Ok(Json::from(test_read))
+}
+
+#[delete("/sensors/<name>")]
+fn delete_sensor(
+    name: &str,
+    client: Option<SocketAddr>,
+    access: AdminAccess,
+    state: &State<AppState>,
+) -> Result<Json<Vec<Sensor>>, ResponseError> {
+    let removed = state.sensors.current().sensor(name).cloned();
+    let sensor_config = state.sensors.remove(name).map_err(sensor_config_error)?;
+    audit(state, client, &access, AuditAction::SensorDeleted, &removed);
+
+    Ok(Json::from(sensor_config.sensors().to_vec()))
+}
+
+#[get("/config")]
+fn get_config(
+    _access: ReadAccess,
+    state: &State<AppState>,
+) -> Result<Json<RecorderConfig>, ResponseError> {
+    let recorder_config = state.db.load_recorder_config().map_err(|err| {
         log::error!("Error loading recorder config: {:?}", err);
         ResponseError::Internal(String::from("Error loading recorder config"))
     })?;
@@ -117,19 +663,26 @@ fn get_config(state: &State<AppState>) -> Result<Json<RecorderConfig>, ResponseE
 #[post("/config", data = "<recorder_config>")]
 fn save_config(
     recorder_config: Json<RecorderConfig>,
+    client: Option<SocketAddr>,
+    access: AdminAccess,
     state: &State<AppState>,
 ) -> Result<Json<RecorderConfig>, ResponseError> {
-    let db = state.db.lock().map_err(|err| {
-        log::warn!("Error retreiving database from state: {}", err);
-        ResponseError::Internal(String::from("Error retreiving database from state"))
-    })?;
-
-    let recorder_config = db
+    recorder_config.validate().map_err(ResponseError::Invalid)?;
+    let previous = state.db.load_recorder_config().ok();
+    let recorder_config = state
+        .db
         .save_recorder_config(recorder_config.into_inner())
         .map_err(|err| {
             log::warn!("Error saving new recorder config: {:?}", err);
             ResponseError::Internal(String::from("Error saving new recorder config"))
         })?;
+    audit(
+        state,
+        client,
+        &access,
+        AuditAction::RecorderConfigSaved,
+        &serde_json::json!({ "previous": previous, "config": recorder_config }),
+    );
 
     let mut current_scheduler = state.scheduler.lock().map_err(|err| {
         log::warn!("Error retreiving scheduler from state: {}", err);
@@ -137,65 +690,364 @@ fn save_config(
     })?;
 
     current_scheduler.stop();
-    // TODO catch error
-    current_scheduler.start(&recorder_config).unwrap();
+    current_scheduler.start(&recorder_config);
 
     Ok(Json::from(recorder_config))
 }
 
+#[catch(401)]
+fn unauthorized() -> ResponseError {
+    ResponseError::Unauthorized(String::from("Missing or unknown API token"))
+}
+
+#[catch(403)]
+fn forbidden() -> ResponseError {
+    ResponseError::Forbidden(String::from("API token is not allowed to do this"))
+}
+
 #[derive(Debug)]
+#[allow(dead_code)]
 enum StartupError {
-    Api(rocket::Error),
+    Api(Box<rocket::Error>),
+    Config(Box<rocket::figment::Error>),
+    Cors(CorsConfigError),
     DatabaseInit(DatabaseInitError),
     DatabaseAccess(DatabaseAccessError),
-    Scheduler(RecorderSchedulerError),
+    SensorConfig(SensorConfigError),
+    TokenCommand(TokenCommandError),
 }
 
 struct AppState {
-    db: Arc<Mutex<Database>>,
+    db: Arc<dyn Storage>,
+    sensors: Arc<SensorConfigStore>,
     scheduler: Arc<Mutex<RecorderScheduler>>,
+    timezone: Tz,
+}
+
+/// Routes, guards and state of the API, ready to launch.
+fn api(rocket: Rocket<Build>, cors: Cors, state: AppState) -> Rocket<Build> {
+    rocket
+        .attach(cors)
+        .manage(Authenticator::new(state.db.clone()))
+        .manage(state)
+        .mount(
+            "/",
+            routes![
+                get_last_temperatures,
+                get_temperatures_since,
+                get_rejected_temperatures_since,
+                get_tank_energy_since,
+                get_burner_cycles,
+                get_daily_stats,
+                get_coverage,
+                get_audit_log,
+                get_config,
+                save_config,
+                reload_sensors,
+                get_sensors,
+                get_sensor,
+                create_sensor,
+                update_sensor,
+                delete_sensor,
+                get_app_health
+            ],
+        )
+        .register("/", catchers![unauthorized, forbidden])
 }
 
 #[rocket::main]
 async fn main() -> Result<(), StartupError> {
+    let rocket = rocket::build();
+    let app_config: AppConfig = rocket
+        .figment()
+        .extract()
+        .map_err(|error| StartupError::Config(Box::new(error)))?;
+
     let args: Vec<String> = env::args().collect();
-    dbg!(args);
+    let ephemeral = args.iter().any(|arg| arg == "--ephemeral");
+
+    let db: Arc<dyn Storage> = if ephemeral {
+        log::warn!("Running in ephemeral mode, recorded data is kept in memory only");
+        Arc::new(MemoryStorage::new())
+    } else {
+        Arc::new(Database::new().map_err(StartupError::DatabaseInit)?)
+    };
 
-    let db = Database::new().map_err(StartupError::DatabaseInit)?;
+    if args.get(1).is_some_and(|arg| arg == "token") {
+        return auth::run_token_command(&args[2..], db.as_ref()).map_err(|error| {
+            if let TokenCommandError::Usage = error {
+                eprintln!("{}", TOKEN_USAGE);
+            }
+            StartupError::TokenCommand(error)
+        });
+    }
+
+    let tokens = db.load_api_tokens().map_err(StartupError::DatabaseAccess)?;
+    if tokens.is_empty() {
+        log::warn!("No API token created, reading is open to everyone who can reach the API");
+    }
+    if !tokens.iter().any(|token| token.scope() == Scope::Admin) {
+        log::warn!(
+            "No admin token created, the configuration can't be changed over the API until one \
+            is created with `boiler-watch-api token create <name> admin`"
+        );
+    }
 
     let recorder_config = &db
         .load_recorder_config()
         .map_err(StartupError::DatabaseAccess)?;
 
-    let db = Arc::new(Mutex::new(db));
+    // TODO use app arguments
+    let sensors =
+        Arc::new(SensorConfigStore::load("Sensor.toml").map_err(StartupError::SensorConfig)?);
+
+    let cache = Arc::new(LastValueCache::default());
+    let timezone = app_config.timezone();
+    log::info!("Using timezone {}", timezone);
+
+    let mut scheduler =
+        RecorderScheduler::new(db.clone(), sensors.clone(), cache.clone(), timezone);
+
+    if let Some(mqtt_config) = &app_config.mqtt {
+        mqtt_subscriber::spawn(mqtt_config, sensors.clone(), cache);
+
+        if let Some(publisher) = MqttPublisher::connect(mqtt_config) {
+            scheduler.add_sink(Arc::new(Mutex::new(publisher)));
+        }
+    }
+
+    scheduler.add_sink(Arc::new(Mutex::new(BurnerCycleDetector::new(db.clone()))));
 
-    let mut scheduler = RecorderScheduler::new();
+    if let Some(influx_config) = app_config.influx {
+        let forwarder = InfluxForwarder::spawn(influx_config, db.clone());
+        scheduler.add_sink(Arc::new(Mutex::new(forwarder)));
+    }
 
-    scheduler
-        .start(recorder_config)
-        .map_err(StartupError::Scheduler)?;
+    scheduler.start(recorder_config);
 
     let scheduler = Arc::new(Mutex::new(scheduler));
 
-    let cors_options = CorsOptions::default();
-    let cors = cors_options.to_cors().unwrap();
+    let cors = app_config.cors.to_cors().map_err(StartupError::Cors)?;
+    let state = AppState {
+        db,
+        sensors,
+        scheduler,
+        timezone,
+    };
 
-    rocket::build()
-        .attach(cors)
-        .manage(AppState { db, scheduler })
-        .mount(
-            "/",
-            routes![
-                get_last_temperatures,
-                get_temperatures_since,
-                get_config,
-                save_config,
-                get_app_health
-            ],
-        )
+    api(rocket, cors, state)
         .launch()
         .await
-        .map_err(StartupError::Api)?;
+        .map_err(|error| StartupError::Api(Box::new(error)))?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_config::CorsConfig;
+    use crate::auth::{generate_token, hash_token, ApiToken};
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::blocking::Client;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    const SENSORS: &str = r#"
+[[sensors]]
+name = "flow"
+path = "/sys/bus/w1/devices/28-1/temperature"
+
+[[sensors]]
+name = "return"
+path = "/sys/bus/w1/devices/28-2/temperature"
+"#;
+
+    /// A local client of the API with in-memory storage, keeping the
+    /// directory of the sensor configuration alive.
+    fn client(cors: CorsConfig) -> (TempDir, Client, Arc<dyn Storage>) {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("Sensor.toml");
+        write(&path, SENSORS).unwrap();
+
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let sensors = Arc::new(SensorConfigStore::load(path.to_str().unwrap()).unwrap());
+        let cache = Arc::new(LastValueCache::default());
+        let scheduler = RecorderScheduler::new(db.clone(), sensors.clone(), cache, Tz::UTC);
+        let state = AppState {
+            db: db.clone(),
+            sensors,
+            scheduler: Arc::new(Mutex::new(scheduler)),
+            timezone: Tz::UTC,
+        };
+
+        let client = Client::tracked(api(rocket::build(), cors.to_cors().unwrap(), state)).unwrap();
+        (dir, client, db)
+    }
+
+    fn create_token(db: &dyn Storage, name: &str, scope: Scope) -> Header<'static> {
+        let token = generate_token();
+        db.save_api_token(&ApiToken::new(
+            name.to_owned(),
+            hash_token(&token),
+            scope,
+            0,
+        ))
+        .unwrap();
+        Header::new("Authorization", format!("Bearer {}", token))
+    }
+
+    #[test]
+    fn requires_tokens_of_the_scope_of_a_route_once_one_exists() {
+        let (_dir, client, db) = client(CorsConfig::default());
+        let read = create_token(db.as_ref(), "gui", Scope::Read);
+        let admin = create_token(db.as_ref(), "admin", Scope::Admin);
+        let config = client.get("/config").header(admin.clone()).dispatch();
+        let config = config.into_string().unwrap();
+
+        assert_eq!(
+            client.get("/sensors").dispatch().status(),
+            Status::Unauthorized
+        );
+        let lower_case = Header::new("Authorization", read.value().replace("Bearer", "bearer"));
+        assert_eq!(
+            client
+                .get("/sensors")
+                .header(lower_case)
+                .dispatch()
+                .status(),
+            Status::Ok
+        );
+
+        let save_config = |token: &Header<'static>| {
+            client
+                .post("/config")
+                .header(ContentType::JSON)
+                .header(token.clone())
+                .body(&config)
+                .dispatch()
+                .status()
+        };
+        assert_eq!(save_config(&read), Status::Forbidden);
+        assert_eq!(save_config(&admin), Status::Ok);
+
+        let delete_sensor = |token: &Header<'static>| {
+            client
+                .delete("/sensors/flow")
+                .header(token.clone())
+                .dispatch()
+                .status()
+        };
+        assert_eq!(delete_sensor(&read), Status::Forbidden);
+        assert_eq!(delete_sensor(&admin), Status::Ok);
+    }
+
+    #[test]
+    fn refuses_admin_routes_until_an_admin_token_exists() {
+        let (_dir, client, _db) = client(CorsConfig::default());
+
+        assert_eq!(client.get("/sensors").dispatch().status(), Status::Ok);
+        assert_eq!(
+            client.delete("/sensors/flow").dispatch().status(),
+            Status::Unauthorized
+        );
+        assert_eq!(client.get("/sensors/flow").dispatch().status(), Status::Ok);
+    }
+
+    #[test]
+    fn rejects_retention_shrinking_towards_coarser_tiers() {
+        let (_dir, client, db) = client(CorsConfig::default());
+        let admin = create_token(db.as_ref(), "admin", Scope::Admin);
+        let save_config = |body: &str| {
+            client
+                .post("/config")
+                .header(ContentType::JSON)
+                .header(admin.clone())
+                .body(body)
+                .dispatch()
+                .status()
+        };
+
+        assert_eq!(
+            save_config(
+                r#"{"interval_seconds":15,"keep_days":30,"keep_days_5m":7,"keep_days_1h":3650}"#
+            ),
+            Status::UnprocessableEntity
+        );
+        assert_eq!(
+            save_config(
+                r#"{"interval_seconds":15,"keep_days":30,"keep_days_5m":365,"keep_days_1h":90}"#
+            ),
+            Status::UnprocessableEntity
+        );
+        assert_eq!(db.load_recorder_config().unwrap().keep_days_5m, 365);
+    }
+
+    #[test]
+    fn never_runs_command_sensors_which_fail_validation() {
+        let (dir, client, db) = client(CorsConfig::default());
+        let admin = create_token(db.as_ref(), "admin", Scope::Admin);
+        let marker = dir.path().join("ran");
+        let sensor = serde_json::json!({
+            "name": "flue",
+            "kind": "command",
+            "command": ["touch", marker.to_str().unwrap()],
+            "timeout_ms": 60_000,
+        })
+        .to_string();
+
+        let created = client
+            .post("/sensors")
+            .header(ContentType::JSON)
+            .header(admin.clone())
+            .body(&sensor)
+            .dispatch();
+        assert_eq!(created.status(), Status::UnprocessableEntity);
+
+        let updated = client
+            .put("/sensors/flow")
+            .header(ContentType::JSON)
+            .header(admin)
+            .body(sensor.replace("flue", "flow"))
+            .dispatch();
+        assert_eq!(updated.status(), Status::UnprocessableEntity);
+
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn answers_preflights_of_allowed_origins_only() {
+        let cors = CorsConfig {
+            allowed_origins: vec![String::from("http://192.168.1.10:3000")],
+            ..CorsConfig::default()
+        };
+        let (_dir, client, _db) = client(cors);
+        let preflight = |origin: &'static str| {
+            client
+                .options("/sensors")
+                .header(Header::new("Origin", origin))
+                .header(Header::new("Access-Control-Request-Method", "GET"))
+                .header(Header::new(
+                    "Access-Control-Request-Headers",
+                    "Authorization",
+                ))
+                .dispatch()
+        };
+
+        assert_eq!(preflight("http://evil.example").status(), Status::Forbidden);
+
+        let response = preflight("http://192.168.1.10:3000");
+        assert!(response.status().class().is_success());
+        let headers = response.headers();
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Origin"),
+            Some("http://192.168.1.10:3000")
+        );
+        assert!(headers
+            .get_one("Access-Control-Allow-Methods")
+            .is_some_and(|methods| methods.contains("GET")));
+        assert!(headers
+            .get_one("Access-Control-Allow-Headers")
+            .is_some_and(|allowed| allowed.to_lowercase().contains("authorization")));
+    }
+}
@@ -0,0 +1,590 @@
+use crate::audit::AuditEntry;
+use crate::auth::ApiToken;
+use crate::burner_cycles::BurnerCycle;
+use crate::database::{DatabaseAccessError, DatabaseStats};
+use crate::sample_filter::RejectedTemperature;
+use crate::storage::{interval_at, IntervalChange, OutboxBatch, RangeStats, Storage};
+use crate::temperature_recorder::{
+    now_millis, RecorderConfig, Resolution, Temperature, TemperaturesByTime,
+};
+
+use chrono_tz::Tz;
+use std::collections::BTreeMap;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Storage keeping everything in memory, lost when the process exits.
+pub struct MemoryStorage {
+    state: RwLock<MemoryState>,
+}
+
+struct MemoryState {
+    config: RecorderConfig,
+    intervals: Vec<IntervalChange>,
+    temperatures: BTreeMap<u64, Vec<Temperature>>,
+    five_minutes: Rollup,
+    hourly: Rollup,
+    rejected: Vec<RejectedTemperature>,
+    outbox: Vec<OutboxBatch>,
+    next_outbox_id: u64,
+    burner_cycles: Vec<BurnerCycle>,
+    audit_log: Vec<AuditEntry>,
+    api_tokens: Vec<ApiToken>,
+}
+
+/// Rollup buckets by bucket start and sensor name.
+type Rollup = BTreeMap<u64, BTreeMap<String, Bucket>>;
+
+#[derive(Clone)]
+struct Bucket {
+    min: f32,
+    max: f32,
+    sum: f64,
+    samples: u64,
+}
+
+impl Bucket {
+    fn new(value: f32) -> Self {
+        Self {
+            min: value,
+            max: value,
+            sum: value as f64,
+            samples: 1,
+        }
+    }
+
+    fn add(&mut self, value: f32) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value as f64;
+        self.samples += 1;
+    }
+
+    fn to_temperature(&self, name: &str) -> Temperature {
+        let average = (self.sum / self.samples as f64) as f32;
+        Temperature::with_range(name.to_owned(), average, self.min, self.max)
+    }
+}
+
+impl MemoryState {
+    fn rollup(&self, resolution: Resolution) -> &Rollup {
+        match resolution {
+            Resolution::FiveMinutes => &self.five_minutes,
+            _ => &self.hourly,
+        }
+    }
+
+    fn rollup_mut(&mut self, resolution: Resolution) -> &mut Rollup {
+        match resolution {
+            Resolution::FiveMinutes => &mut self.five_minutes,
+            _ => &mut self.hourly,
+        }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        let config = RecorderConfig::new(
+            15,
+            30,
+            RecorderConfig::default_keep_days_5m(),
+            RecorderConfig::default_keep_days_1h(),
+        );
+        let state = MemoryState {
+            intervals: vec![IntervalChange {
+                since: 0,
+                interval_seconds: config.interval_seconds,
+            }],
+            config,
+            temperatures: BTreeMap::new(),
+            five_minutes: BTreeMap::new(),
+            hourly: BTreeMap::new(),
+            rejected: vec![],
+            outbox: vec![],
+            next_outbox_id: 1,
+            burner_cycles: vec![],
+            audit_log: vec![],
+            api_tokens: vec![],
+        };
+
+        Self {
+            state: RwLock::new(state),
+        }
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, MemoryState>, DatabaseAccessError> {
+        self.state
+            .read()
+            .map_err(|err| DatabaseAccessError::Lock(err.to_string()))
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, MemoryState>, DatabaseAccessError> {
+        self.state
+            .write()
+            .map_err(|err| DatabaseAccessError::Lock(err.to_string()))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load_recorder_config(&self) -> Result<RecorderConfig, DatabaseAccessError> {
+        Ok(self.read()?.config.clone())
+    }
+
+    fn save_recorder_config(
+        &self,
+        config: RecorderConfig,
+    ) -> Result<RecorderConfig, DatabaseAccessError> {
+        let now = now_millis().map_err(DatabaseAccessError::Date)?;
+        let mut state = self.write()?;
+
+        if state.config.interval_seconds != config.interval_seconds {
+            state.intervals.retain(|change| change.since != now);
+            state.intervals.push(IntervalChange {
+                since: now,
+                interval_seconds: config.interval_seconds,
+            });
+        }
+
+        state.config = config.clone();
+        Ok(config)
+    }
+
+    fn load_interval_history(&self) -> Result<Vec<IntervalChange>, DatabaseAccessError> {
+        Ok(self.read()?.intervals.clone())
+    }
+
+    fn save_temperatures(
+        &self,
+        temperatures_by_time: TemperaturesByTime,
+    ) -> Result<(), DatabaseAccessError> {
+        self.write()?
+            .temperatures
+            .entry(temperatures_by_time.date())
+            .or_default()
+            .extend(temperatures_by_time.temperatures());
+        Ok(())
+    }
+
+    fn load_temperatures_since(
+        &self,
+        since: u64,
+    ) -> Result<Vec<TemperaturesByTime>, DatabaseAccessError> {
+        self.load_temperatures_between(since, u64::MAX)
+    }
+
+    fn load_temperatures_between(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<TemperaturesByTime>, DatabaseAccessError> {
+        let state = self.read()?;
+        let now = now_millis().map_err(DatabaseAccessError::Date)?;
+
+        let temperatures = match state.config.resolution_since(from, now) {
+            Resolution::Raw => state
+                .temperatures
+                .range(from..to.max(from))
+                .map(|(date, temperatures)| TemperaturesByTime::new(*date, temperatures.clone()))
+                .collect(),
+            rollup => {
+                let bucket_start = from - from % rollup.bucket_millis();
+                state
+                    .rollup(rollup)
+                    .range(bucket_start..to.max(bucket_start))
+                    .map(|(date, buckets)| {
+                        let temperatures = buckets
+                            .iter()
+                            .map(|(name, bucket)| bucket.to_temperature(name))
+                            .collect();
+                        TemperaturesByTime::new(*date, temperatures)
+                    })
+                    .collect()
+            }
+        };
+
+        Ok(temperatures)
+    }
+
+    fn load_last_temperature(&self) -> Result<Option<TemperaturesByTime>, DatabaseAccessError> {
+        Ok(self
+            .read()?
+            .temperatures
+            .last_key_value()
+            .map(|(date, temperatures)| TemperaturesByTime::new(*date, temperatures.clone())))
+    }
+
+    fn update_rollups(&self) -> Result<(), DatabaseAccessError> {
+        let mut state = self.write()?;
+
+        for resolution in Resolution::ROLLUPS {
+            let bucket_millis = resolution.bucket_millis();
+            let start = state
+                .rollup(resolution)
+                .last_key_value()
+                .map(|(date, _)| *date)
+                .unwrap_or(0);
+
+            let mut recomputed = Rollup::new();
+            for (date, temperatures) in state.temperatures.range(start..) {
+                let buckets = recomputed.entry(date - date % bucket_millis).or_default();
+                for temperature in temperatures {
+                    buckets
+                        .entry(temperature.name())
+                        .and_modify(|bucket| bucket.add(temperature.value()))
+                        .or_insert_with(|| Bucket::new(temperature.value()));
+                }
+            }
+
+            state.rollup_mut(resolution).extend(recomputed);
+        }
+
+        Ok(())
+    }
+
+    fn delete_old_temperatures(&self, timezone: &Tz) -> Result<usize, DatabaseAccessError> {
+        let mut state = self.write()?;
+        let now = now_millis().map_err(DatabaseAccessError::Date)?;
+        let cutoff =
+            |resolution: Resolution| state.config.retention_cutoff(resolution, now, timezone);
+
+        let raw_cutoff = cutoff(Resolution::Raw);
+        let five_minutes_cutoff = cutoff(Resolution::FiveMinutes);
+        let hourly_cutoff = cutoff(Resolution::Hourly);
+
+        let kept = state.temperatures.split_off(&raw_cutoff);
+        let deleted = std::mem::replace(&mut state.temperatures, kept);
+        let mut count: usize = deleted.values().map(Vec::len).sum();
+
+        for (resolution, cutoff) in [
+            (Resolution::FiveMinutes, five_minutes_cutoff),
+            (Resolution::Hourly, hourly_cutoff),
+        ] {
+            let rollup = state.rollup_mut(resolution);
+            let kept = rollup.split_off(&cutoff);
+            let deleted = std::mem::replace(rollup, kept);
+            count += deleted.values().map(BTreeMap::len).sum::<usize>();
+        }
+
+        let rejected_count = state.rejected.len();
+        state
+            .rejected
+            .retain(|rejected| rejected.date() >= raw_cutoff);
+        count += rejected_count - state.rejected.len();
+
+        let cycles_count = state.burner_cycles.len();
+        state
+            .burner_cycles
+            .retain(|cycle| cycle.start() >= hourly_cutoff);
+        count += cycles_count - state.burner_cycles.len();
+
+        Ok(count)
+    }
+
+    fn save_rejected_temperatures(
+        &self,
+        rejected: &[RejectedTemperature],
+    ) -> Result<(), DatabaseAccessError> {
+        self.write()?.rejected.extend_from_slice(rejected);
+        Ok(())
+    }
+
+    fn load_rejected_temperatures_since(
+        &self,
+        since: u64,
+    ) -> Result<Vec<RejectedTemperature>, DatabaseAccessError> {
+        Ok(self
+            .read()?
+            .rejected
+            .iter()
+            .filter(|rejected| rejected.date() >= since)
+            .cloned()
+            .collect())
+    }
+
+    fn load_range_stats(
+        &self,
+        from: u64,
+        to: u64,
+        sensor: Option<&str>,
+        threshold: Option<f32>,
+    ) -> Result<Vec<RangeStats>, DatabaseAccessError> {
+        let state = self.read()?;
+        let now = now_millis().map_err(DatabaseAccessError::Date)?;
+        let resolution = state.config.resolution_since(from, now);
+
+        // (bucket, weight, seconds) per sensor, raw temperatures are buckets
+        // of their own covering the interval used when they were recorded
+        let mut buckets: BTreeMap<String, Vec<(Bucket, u64, u64)>> = BTreeMap::new();
+        match resolution {
+            Resolution::Raw => {
+                for (date, temperatures) in state.temperatures.range(from..to) {
+                    let seconds = interval_at(&state.intervals, *date).unwrap_or_default();
+                    for temperature in temperatures {
+                        buckets.entry(temperature.name()).or_default().push((
+                            Bucket::new(temperature.value()),
+                            1,
+                            seconds as u64,
+                        ));
+                    }
+                }
+            }
+            rollup => {
+                for rollup_buckets in state.rollup(rollup).range(from..to).map(|(_, b)| b) {
+                    for (name, bucket) in rollup_buckets {
+                        buckets.entry(name.to_owned()).or_default().push((
+                            bucket.clone(),
+                            bucket.samples,
+                            rollup.bucket_millis() / 1000,
+                        ));
+                    }
+                }
+            }
+        }
+
+        let stats = buckets
+            .into_iter()
+            .filter(|(name, _)| sensor.is_none_or(|sensor| sensor == name))
+            .map(|(name, buckets)| {
+                let average = |bucket: &Bucket| bucket.sum / bucket.samples as f64;
+                let weight: u64 = buckets.iter().map(|(_, weight, _)| weight).sum();
+                let sum: f64 = buckets
+                    .iter()
+                    .map(|(bucket, weight, _)| average(bucket) * *weight as f64)
+                    .sum();
+                let above = buckets
+                    .iter()
+                    .filter(|(bucket, _, _)| threshold.is_some_and(|t| average(bucket) > t as f64))
+                    .map(|(_, _, seconds)| seconds)
+                    .sum();
+
+                RangeStats {
+                    name,
+                    min: buckets
+                        .iter()
+                        .map(|(b, _, _)| b.min)
+                        .fold(f32::MAX, f32::min),
+                    max: buckets
+                        .iter()
+                        .map(|(b, _, _)| b.max)
+                        .fold(f32::MIN, f32::max),
+                    mean: (sum / weight as f64) as f32,
+                    covered_seconds: buckets.iter().map(|(_, _, seconds)| seconds).sum(),
+                    above_seconds: above,
+                }
+            })
+            .collect();
+
+        Ok(stats)
+    }
+
+    fn save_burner_cycle(&self, cycle: &BurnerCycle) -> Result<(), DatabaseAccessError> {
+        let mut state = self.write()?;
+        // a cycle with the same start replaces the stored one, like in SQLite
+        match state
+            .burner_cycles
+            .iter_mut()
+            .find(|saved| saved.start() == cycle.start())
+        {
+            Some(saved) => *saved = cycle.clone(),
+            None => state.burner_cycles.push(cycle.clone()),
+        }
+        Ok(())
+    }
+
+    fn load_burner_cycles(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<BurnerCycle>, DatabaseAccessError> {
+        Ok(self
+            .read()?
+            .burner_cycles
+            .iter()
+            .filter(|cycle| cycle.start() >= from && cycle.start() <= to)
+            .cloned()
+            .collect())
+    }
+
+    fn save_audit_entry(&self, entry: &AuditEntry) -> Result<(), DatabaseAccessError> {
+        self.write()?.audit_log.push(entry.clone());
+        Ok(())
+    }
+
+    fn load_audit_entries(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<AuditEntry>, DatabaseAccessError> {
+        Ok(self
+            .read()?
+            .audit_log
+            .iter()
+            .filter(|entry| entry.date() >= from && entry.date() <= to)
+            .cloned()
+            .collect())
+    }
+
+    fn save_api_token(&self, token: &ApiToken) -> Result<(), DatabaseAccessError> {
+        self.write()?.api_tokens.push(token.clone());
+        Ok(())
+    }
+
+    fn load_api_tokens(&self) -> Result<Vec<ApiToken>, DatabaseAccessError> {
+        Ok(self.read()?.api_tokens.clone())
+    }
+
+    fn delete_api_token(&self, name: &str) -> Result<usize, DatabaseAccessError> {
+        let mut state = self.write()?;
+        let count = state.api_tokens.len();
+        state.api_tokens.retain(|token| token.name() != name);
+        Ok(count - state.api_tokens.len())
+    }
+
+    fn push_outbox(&self, body: &str) -> Result<(), DatabaseAccessError> {
+        let mut state = self.write()?;
+        let id = state.next_outbox_id;
+        state.next_outbox_id += 1;
+        state.outbox.push(OutboxBatch {
+            id,
+            body: body.to_owned(),
+        });
+        Ok(())
+    }
+
+    fn load_outbox(&self, limit: usize) -> Result<Vec<OutboxBatch>, DatabaseAccessError> {
+        Ok(self.read()?.outbox.iter().take(limit).cloned().collect())
+    }
+
+    fn delete_outbox(&self, id: u64) -> Result<usize, DatabaseAccessError> {
+        let mut state = self.write()?;
+        let count = state.outbox.len();
+        state.outbox.retain(|batch| batch.id > id);
+        Ok(count - state.outbox.len())
+    }
+
+    fn trim_outbox(&self, keep: usize) -> Result<usize, DatabaseAccessError> {
+        let mut state = self.write()?;
+        let count = state.outbox.len().saturating_sub(keep);
+        state.outbox.drain(..count);
+        Ok(count)
+    }
+
+    fn stats(&self) -> DatabaseStats {
+        DatabaseStats::default()
+    }
+
+    fn size_on_disk(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temperature_recorder::MILLIS_PER_DAY;
+
+    fn temperatures(date: u64, values: &[(&str, f32)]) -> TemperaturesByTime {
+        TemperaturesByTime::new(
+            date,
+            values
+                .iter()
+                .map(|(name, value)| Temperature::new(String::from(*name), *value))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn loads_saved_temperatures() {
+        let storage = MemoryStorage::new();
+        let now = now_millis().unwrap();
+
+        storage
+            .save_temperatures(temperatures(
+                now - 2000,
+                &[("flow", 60.0), ("return", 40.0)],
+            ))
+            .unwrap();
+        storage
+            .save_temperatures(temperatures(
+                now - 1000,
+                &[("flow", 61.0), ("return", 41.0)],
+            ))
+            .unwrap();
+
+        let last = storage.load_last_temperature().unwrap().unwrap();
+        assert_eq!(last.date(), now - 1000);
+        assert_eq!(last.temperatures()[0].value(), 61.0);
+
+        let since = storage.load_temperatures_since(now - 1500).unwrap();
+        assert_eq!(since.len(), 1);
+        assert_eq!(since[0].temperatures().len(), 2);
+    }
+
+    #[test]
+    fn loads_rollups_for_ranges_older_than_raw_retention() {
+        let storage = MemoryStorage::new();
+        let now = now_millis().unwrap();
+        let bucket_start = now - 40 * MILLIS_PER_DAY;
+        let bucket_start = bucket_start - bucket_start % Resolution::Hourly.bucket_millis();
+
+        storage
+            .save_temperatures(temperatures(bucket_start, &[("flow", 50.0)]))
+            .unwrap();
+        storage
+            .save_temperatures(temperatures(bucket_start + 1000, &[("flow", 60.0)]))
+            .unwrap();
+        storage.update_rollups().unwrap();
+
+        let since = storage.load_temperatures_since(bucket_start).unwrap();
+        assert_eq!(since.len(), 1);
+        assert_eq!(since[0].date(), bucket_start);
+        assert_eq!(since[0].temperatures()[0].value(), 55.0);
+    }
+
+    #[test]
+    fn deletes_raw_temperatures_but_keeps_rollups() {
+        let storage = MemoryStorage::new();
+        let now = now_millis().unwrap();
+        let old = now - 40 * MILLIS_PER_DAY;
+
+        storage
+            .save_temperatures(temperatures(old, &[("flow", 50.0)]))
+            .unwrap();
+        storage
+            .save_temperatures(temperatures(now, &[("flow", 60.0)]))
+            .unwrap();
+        storage.update_rollups().unwrap();
+
+        assert_eq!(storage.delete_old_temperatures(&Tz::UTC).unwrap(), 1);
+        assert_eq!(storage.load_temperatures_since(old).unwrap().len(), 2);
+        assert_eq!(storage.load_temperatures_since(now).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn replaces_burner_cycles_with_the_same_start() {
+        let storage = MemoryStorage::new();
+
+        storage
+            .save_burner_cycle(&BurnerCycle::new(1_000, 2_000))
+            .unwrap();
+        storage
+            .save_burner_cycle(&BurnerCycle::new(5_000, 6_000))
+            .unwrap();
+        storage
+            .save_burner_cycle(&BurnerCycle::new(1_000, 3_000))
+            .unwrap();
+
+        assert_eq!(
+            storage.load_burner_cycles(0, 10_000).unwrap(),
+            vec![
+                BurnerCycle::new(1_000, 3_000),
+                BurnerCycle::new(5_000, 6_000)
+            ]
+        );
+    }
+}
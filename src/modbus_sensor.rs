@@ -0,0 +1,241 @@
+use crate::sensor_config::ModbusDataType;
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+
+#[derive(Debug)]
+pub enum ModbusError {
+    Connect(io::Error),
+    Io(io::Error),
+    /// Exception code returned by the device
+    Exception(u8),
+    InvalidResponse(String),
+}
+
+/// Reads a value from holding registers of a Modbus TCP device, with 32 bit
+/// values spanning two registers, high word first.
+pub fn read_value(
+    address: &str,
+    unit_id: u8,
+    register: u16,
+    data_type: ModbusDataType,
+    timeout: Duration,
+) -> Result<f32, ModbusError> {
+    let count = match data_type {
+        ModbusDataType::U16 | ModbusDataType::I16 => 1,
+        ModbusDataType::U32 | ModbusDataType::I32 | ModbusDataType::F32 => 2,
+    };
+
+    let registers = read_holding_registers(address, unit_id, register, count, timeout)?;
+
+    decode(&registers, data_type)
+}
+
+/// Value of the registers, which for floats may also be NaN or infinite
+/// and then can't be stored.
+fn decode(registers: &[u16], data_type: ModbusDataType) -> Result<f32, ModbusError> {
+    let double_word = || ((registers[0] as u32) << 16) | registers[1] as u32;
+
+    let value = match data_type {
+        ModbusDataType::U16 => registers[0] as f32,
+        ModbusDataType::I16 => registers[0] as i16 as f32,
+        ModbusDataType::U32 => double_word() as f32,
+        ModbusDataType::I32 => double_word() as i32 as f32,
+        ModbusDataType::F32 => f32::from_bits(double_word()),
+    };
+
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(ModbusError::InvalidResponse(format!(
+            "Registers {:?} are no finite {:?}",
+            registers, data_type
+        )))
+    }
+}
+
+pub fn read_holding_registers(
+    address: &str,
+    unit_id: u8,
+    register: u16,
+    count: u16,
+    timeout: Duration,
+) -> Result<Vec<u16>, ModbusError> {
+    let socket_address = address
+        .to_socket_addrs()
+        .map_err(ModbusError::Connect)?
+        .next()
+        .ok_or_else(|| {
+            ModbusError::Connect(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No address found for {}", address),
+            ))
+        })?;
+
+    let mut stream =
+        TcpStream::connect_timeout(&socket_address, timeout).map_err(ModbusError::Connect)?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(ModbusError::Io)?;
+    stream
+        .set_write_timeout(Some(timeout))
+        .map_err(ModbusError::Io)?;
+
+    // MBAP header: transaction id, protocol id 0, length of the rest, unit id
+    let transaction_id: u16 = 1;
+    let mut request = vec![];
+    request.extend_from_slice(&transaction_id.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&6u16.to_be_bytes());
+    request.push(unit_id);
+    request.push(READ_HOLDING_REGISTERS);
+    request.extend_from_slice(&register.to_be_bytes());
+    request.extend_from_slice(&count.to_be_bytes());
+
+    stream.write_all(&request).map_err(ModbusError::Io)?;
+
+    let mut header = [0u8; 7];
+    stream.read_exact(&mut header).map_err(ModbusError::Io)?;
+
+    if u16::from_be_bytes([header[0], header[1]]) != transaction_id {
+        return Err(ModbusError::InvalidResponse(String::from(
+            "Transaction id does not match",
+        )));
+    }
+
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if length < 2 {
+        return Err(ModbusError::InvalidResponse(format!(
+            "Response length {} too short",
+            length
+        )));
+    }
+
+    let mut pdu = vec![0u8; length - 1];
+    stream.read_exact(&mut pdu).map_err(ModbusError::Io)?;
+
+    match pdu[0] {
+        READ_HOLDING_REGISTERS => {}
+        function if function == READ_HOLDING_REGISTERS | 0x80 => {
+            return Err(ModbusError::Exception(pdu.get(1).copied().unwrap_or(0)));
+        }
+        function => {
+            return Err(ModbusError::InvalidResponse(format!(
+                "Unexpected function code {}",
+                function
+            )));
+        }
+    }
+
+    let byte_count = count as usize * 2;
+    if pdu.len() != 2 + byte_count || pdu[1] as usize != byte_count {
+        return Err(ModbusError::InvalidResponse(String::from(
+            "Unexpected number of register bytes",
+        )));
+    }
+
+    Ok(pdu[2..]
+        .chunks_exact(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answers one read request with `registers`, or with exception code 2
+    /// if `registers` is empty, and returns the address of the stand-in.
+    fn serve(registers: Vec<u16>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 12];
+            stream.read_exact(&mut request).unwrap();
+
+            let pdu = if registers.is_empty() {
+                vec![request[7] | 0x80, 2]
+            } else {
+                let mut pdu = vec![request[7], registers.len() as u8 * 2];
+                for register in registers {
+                    pdu.extend_from_slice(&register.to_be_bytes());
+                }
+                pdu
+            };
+
+            let mut response = request[0..4].to_vec();
+            response.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+            response.push(request[6]);
+            response.extend_from_slice(&pdu);
+            stream.write_all(&response).unwrap();
+        });
+
+        address.to_string()
+    }
+
+    #[test]
+    fn reads_signed_register() {
+        let address = serve(vec![(-55i16) as u16]);
+
+        let value = read_value(
+            &address,
+            1,
+            100,
+            ModbusDataType::I16,
+            Duration::from_secs(2),
+        )
+        .unwrap();
+        assert_eq!(value, -55.0);
+    }
+
+    #[test]
+    fn reads_float_from_two_registers() {
+        let bits = 62.5f32.to_bits();
+        let address = serve(vec![(bits >> 16) as u16, bits as u16]);
+
+        let value = read_value(
+            &address,
+            1,
+            100,
+            ModbusDataType::F32,
+            Duration::from_secs(2),
+        )
+        .unwrap();
+        assert_eq!(value, 62.5);
+    }
+
+    #[test]
+    fn rejects_floats_which_are_not_finite() {
+        for value in [f32::NAN, f32::INFINITY] {
+            let bits = value.to_bits();
+            let registers = [(bits >> 16) as u16, bits as u16];
+
+            assert!(matches!(
+                decode(&registers, ModbusDataType::F32),
+                Err(ModbusError::InvalidResponse(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn reports_exceptions() {
+        let address = serve(vec![]);
+
+        let error = read_value(
+            &address,
+            1,
+            100,
+            ModbusDataType::U16,
+            Duration::from_secs(2),
+        )
+        .unwrap_err();
+        assert!(matches!(error, ModbusError::Exception(2)));
+    }
+}
@@ -0,0 +1,361 @@
+use crate::app_config::MqttConfig;
+use crate::recorder_scheduler::TemperatureSink;
+use crate::sensor_config::{Sensor, SensorConfig};
+use crate::temperature_recorder::TemperaturesByTime;
+
+use rumqttc::{Client, Event, LastWill, Packet, QoS};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Messages of a few ticks fit in, so a short outage of the broker never
+/// blocks the scheduler
+const QUEUE_CAPACITY: usize = 1000;
+
+/// Pause before connecting again after the broker went away
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+const DEFAULT_UNIT: &str = "°C";
+
+/// Units Home Assistant accepts for the temperature device class
+const TEMPERATURE_UNITS: [&str; 3] = ["°C", "°F", "K"];
+
+/// Publishes every reading to the broker and announces the sensors to
+/// Home Assistant by MQTT discovery.
+pub struct MqttPublisher {
+    client: Client,
+    connected: Arc<AtomicBool>,
+    discovery: Discovery,
+}
+
+#[derive(Debug, PartialEq)]
+struct Message {
+    topic: String,
+    payload: String,
+    retain: bool,
+}
+
+/// What Home Assistant has been told so far, so only changes are published.
+struct Discovery {
+    topic: String,
+    discovery_prefix: String,
+    node_id: String,
+    stale_millis: u64,
+    /// Discovery config last published per sensor
+    configs: HashMap<String, String>,
+    last_seen: HashMap<String, u64>,
+    available: HashMap<String, bool>,
+}
+
+impl MqttPublisher {
+    /// Connects to the broker, if publishing is configured at all.
+    pub fn connect(config: &MqttConfig) -> Option<Self> {
+        let topic = config.publish_topic.as_ref()?;
+        let discovery = Discovery::new(config, topic);
+
+        let mut options = config.options("publisher");
+        options.set_last_will(LastWill::new(
+            discovery.status_topic(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        let (client, mut connection) = Client::new(options, QUEUE_CAPACITY);
+
+        let connected = Arc::new(AtomicBool::new(false));
+        let reconnected = connected.clone();
+        thread::spawn(move || {
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        log::info!("Connected to MQTT broker for publishing");
+                        reconnected.store(true, Ordering::SeqCst);
+                    }
+                    Ok(_) => {}
+                    Err(error) => {
+                        log::warn!("Lost connection to MQTT broker: {:?}", error);
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+
+        Some(Self {
+            client,
+            connected,
+            discovery,
+        })
+    }
+
+    fn publish(&self, message: Message) {
+        let result = self.client.try_publish(
+            &message.topic,
+            QoS::AtLeastOnce,
+            message.retain,
+            message.payload,
+        );
+
+        if let Err(error) = result {
+            log::warn!("Error publishing to {}: {:?}", message.topic, error);
+        }
+    }
+}
+
+impl TemperatureSink for MqttPublisher {
+    fn send(&mut self, sensor_config: &SensorConfig, temperatures: &TemperaturesByTime) {
+        // retained messages of the last will may have replaced ours meanwhile
+        if self.connected.swap(false, Ordering::SeqCst) {
+            self.discovery.reset();
+            self.publish(Message {
+                topic: self.discovery.status_topic(),
+                payload: String::from("online"),
+                retain: true,
+            });
+        }
+
+        for message in self.discovery.messages(sensor_config, temperatures) {
+            self.publish(message);
+        }
+    }
+}
+
+impl Discovery {
+    fn new(config: &MqttConfig, topic: &str) -> Self {
+        Self {
+            topic: topic.trim_end_matches('/').to_owned(),
+            discovery_prefix: config.discovery_prefix.trim_end_matches('/').to_owned(),
+            node_id: object_id(&config.client_id),
+            stale_millis: config.stale_seconds * 1000,
+            configs: HashMap::new(),
+            last_seen: HashMap::new(),
+            available: HashMap::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.configs.clear();
+        self.available.clear();
+    }
+
+    fn status_topic(&self) -> String {
+        format!("{}/status", self.topic)
+    }
+
+    fn sensor_topic(&self, name: &str, suffix: &str) -> String {
+        format!("{}/{}/{}", self.topic, object_id(name), suffix)
+    }
+
+    fn config_topic(&self, name: &str) -> String {
+        format!(
+            "{}/sensor/{}/{}/config",
+            self.discovery_prefix,
+            self.node_id,
+            object_id(name)
+        )
+    }
+
+    fn config(&self, sensor: &Sensor) -> String {
+        let unit = sensor.metadata().unit().unwrap_or(DEFAULT_UNIT);
+        let mut config = json!({
+            "name": sensor.name(),
+            "unique_id": format!("{}_{}", self.node_id, object_id(sensor.name())),
+            "state_topic": self.sensor_topic(sensor.name(), "state"),
+            "unit_of_measurement": unit,
+            "state_class": "measurement",
+            "availability": [
+                { "topic": self.status_topic() },
+                { "topic": self.sensor_topic(sensor.name(), "availability") },
+            ],
+            "availability_mode": "all",
+            "device": {
+                "identifiers": [self.node_id],
+                "name": "Boiler Watch",
+            },
+        });
+
+        if TEMPERATURE_UNITS.contains(&unit) {
+            config["device_class"] = json!("temperature");
+        }
+
+        config.to_string()
+    }
+
+    /// Discovery config of new or changed sensors, removal of deleted ones,
+    /// the readings and changes of availability.
+    fn messages(
+        &mut self,
+        sensor_config: &SensorConfig,
+        temperatures: &TemperaturesByTime,
+    ) -> Vec<Message> {
+        let mut messages = vec![];
+
+        for sensor in sensor_config.sensors() {
+            let config = self.config(sensor);
+            if self.configs.get(sensor.name()) != Some(&config) {
+                messages.push(Message {
+                    topic: self.config_topic(sensor.name()),
+                    payload: config.clone(),
+                    retain: true,
+                });
+                self.configs.insert(sensor.name().to_owned(), config);
+            }
+        }
+
+        let removed: Vec<String> = self
+            .configs
+            .keys()
+            .filter(|name| sensor_config.sensor(name).is_none())
+            .cloned()
+            .collect();
+        for name in removed {
+            // an empty config makes Home Assistant delete the entity
+            messages.push(Message {
+                topic: self.config_topic(&name),
+                payload: String::new(),
+                retain: true,
+            });
+            self.configs.remove(&name);
+            self.last_seen.remove(&name);
+            self.available.remove(&name);
+        }
+
+        for temperature in temperatures.temperatures() {
+            messages.push(Message {
+                topic: self.sensor_topic(&temperature.name(), "state"),
+                payload: temperature.value().to_string(),
+                retain: false,
+            });
+            self.last_seen
+                .insert(temperature.name(), temperatures.date());
+        }
+
+        for sensor in sensor_config.sensors() {
+            let available = self
+                .last_seen
+                .get(sensor.name())
+                .is_some_and(|seen| temperatures.date().saturating_sub(*seen) <= self.stale_millis);
+
+            if self.available.get(sensor.name()) != Some(&available) {
+                messages.push(Message {
+                    topic: self.sensor_topic(sensor.name(), "availability"),
+                    payload: String::from(if available { "online" } else { "offline" }),
+                    retain: true,
+                });
+                self.available.insert(sensor.name().to_owned(), available);
+            }
+        }
+
+        messages
+    }
+}
+
+/// Home Assistant only accepts letters, digits, `_` and `-` in ids.
+fn object_id(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temperature_recorder::Temperature;
+
+    fn discovery() -> Discovery {
+        let config: MqttConfig = toml::from_str(
+            r#"
+            host = "localhost"
+            publish_topic = "boiler-watch"
+            stale_seconds = 60
+        "#,
+        )
+        .unwrap();
+
+        Discovery::new(&config, config.publish_topic.as_ref().unwrap())
+    }
+
+    fn topics(messages: &[Message]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.topic.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn announces_sensors_once_and_publishes_readings() {
+        let config = SensorConfig::parse(
+            r#"
+            [[sensors]]
+            name = "tank top"
+            path = "/sys/bus/w1/devices/28-1/temperature"
+        "#,
+        )
+        .unwrap();
+        let mut discovery = discovery();
+
+        let temperatures = TemperaturesByTime::new(
+            1_000,
+            vec![Temperature::new(String::from("tank top"), 61.5)],
+        );
+        let messages = discovery.messages(&config, &temperatures);
+        assert_eq!(
+            topics(&messages),
+            vec![
+                "homeassistant/sensor/boiler-watch-api/tank_top/config",
+                "boiler-watch/tank_top/state",
+                "boiler-watch/tank_top/availability",
+            ]
+        );
+        assert!(messages[0]
+            .payload
+            .contains(r#""device_class":"temperature""#));
+        assert_eq!(messages[1].payload, "61.5");
+        assert_eq!(messages[2].payload, "online");
+
+        let temperatures = TemperaturesByTime::new(
+            31_000,
+            vec![Temperature::new(String::from("tank top"), 61.0)],
+        );
+        let messages = discovery.messages(&config, &temperatures);
+        assert_eq!(topics(&messages), vec!["boiler-watch/tank_top/state"]);
+    }
+
+    #[test]
+    fn marks_stale_sensors_unavailable() {
+        let config = SensorConfig::parse(
+            r#"
+            [[sensors]]
+            name = "flow"
+            path = "/sys/bus/w1/devices/28-1/temperature"
+        "#,
+        )
+        .unwrap();
+        let mut discovery = discovery();
+
+        let temperatures =
+            TemperaturesByTime::new(0, vec![Temperature::new(String::from("flow"), 45.0)]);
+        discovery.messages(&config, &temperatures);
+
+        let messages = discovery.messages(&config, &TemperaturesByTime::new(60_000, vec![]));
+        assert!(messages.is_empty());
+
+        let messages = discovery.messages(&config, &TemperaturesByTime::new(61_000, vec![]));
+        assert_eq!(
+            messages,
+            vec![Message {
+                topic: String::from("boiler-watch/flow/availability"),
+                payload: String::from("offline"),
+                retain: true,
+            }]
+        );
+    }
+}
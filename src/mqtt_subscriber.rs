@@ -0,0 +1,345 @@
+use crate::app_config::MqttConfig;
+use crate::http_sensor::LastValueCache;
+use crate::sensor_config::{SensorConfig, SensorConfigStore, SensorKind, ValueFormat};
+use crate::temperature_reader::parse_value;
+
+use rumqttc::{Client, Event, Packet, QoS};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often subscriptions are matched with the sensor configuration
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Pause before connecting again after the broker went away
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Subscribes to the topics of `mqtt` sensors and keeps the last value
+/// received for each of them in `cache`, where the scheduler picks it up
+/// on its next tick, so pushed readings share the timeline of all others.
+///
+/// Runs until the app exits and reconnects whenever the broker is lost.
+pub fn spawn(config: &MqttConfig, sensors: Arc<SensorConfigStore>, cache: Arc<LastValueCache>) {
+    let (client, mut connection) = Client::new(config.options("subscriber"), 10);
+    let subscribed = Arc::new(Mutex::new(HashSet::<String>::new()));
+
+    let sync_client = client.clone();
+    let sync_sensors = sensors.clone();
+    let sync_subscribed = subscribed.clone();
+    thread::spawn(move || loop {
+        sync_subscriptions(&sync_client, &sync_sensors.current(), &sync_subscribed);
+        thread::sleep(SYNC_INTERVAL);
+    });
+
+    thread::spawn(move || {
+        for notification in connection.iter() {
+            match notification {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    log::info!("Connected to MQTT broker");
+                    // the broker forgets subscriptions of clean sessions, so
+                    // they are renewed instead of forgotten, which would leave
+                    // topics subscribed before the first connection behind
+                    resubscribe(&client, &subscribed);
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let payload = String::from_utf8_lossy(&publish.payload);
+                    receive(&sensors.current(), &cache, &publish.topic, &payload);
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    log::warn!("Lost connection to MQTT broker: {:?}", error);
+                    thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        }
+    });
+}
+
+/// Subscribes to topics of new `mqtt` sensors and unsubscribes from topics
+/// no sensor uses anymore.
+fn sync_subscriptions(
+    client: &Client,
+    sensor_config: &SensorConfig,
+    subscribed: &Mutex<HashSet<String>>,
+) {
+    let topics: HashSet<String> = sensor_config
+        .sensors()
+        .iter()
+        .filter(|sensor| sensor.kind() == SensorKind::Mqtt)
+        .map(|sensor| sensor.topic().to_owned())
+        .collect();
+
+    let mut subscribed = match subscribed.lock() {
+        Ok(subscribed) => subscribed,
+        Err(_) => return,
+    };
+
+    for topic in topics.difference(&subscribed.clone()) {
+        match client.try_subscribe(topic, QoS::AtMostOnce) {
+            Ok(()) => {
+                log::debug!("Subscribed to MQTT topic {}", topic);
+                subscribed.insert(topic.to_owned());
+            }
+            Err(error) => log::warn!("Error subscribing to MQTT topic {}: {:?}", topic, error),
+        }
+    }
+
+    for topic in subscribed.clone().difference(&topics) {
+        if client.try_unsubscribe(topic).is_ok() {
+            subscribed.remove(topic);
+        }
+    }
+}
+
+/// Subscribes again to every topic subscribed so far.
+fn resubscribe(client: &Client, subscribed: &Mutex<HashSet<String>>) {
+    let subscribed = match subscribed.lock() {
+        Ok(subscribed) => subscribed,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+
+    for topic in subscribed.iter() {
+        if let Err(error) = client.try_subscribe(topic, QoS::AtMostOnce) {
+            log::warn!("Error subscribing to MQTT topic {}: {:?}", topic, error);
+        }
+    }
+}
+
+/// Stores the value of a message for every `mqtt` sensor of its topic.
+fn receive(sensor_config: &SensorConfig, cache: &LastValueCache, topic: &str, payload: &str) {
+    let sensors = sensor_config
+        .sensors()
+        .iter()
+        .filter(|sensor| sensor.kind() == SensorKind::Mqtt && sensor.topic() == topic);
+
+    for sensor in sensors {
+        let default_format = if sensor.json_pointer().is_empty() {
+            ValueFormat::Plain
+        } else {
+            ValueFormat::Json
+        };
+        let format = sensor.format().unwrap_or(default_format);
+
+        match parse_value(payload, format, sensor.json_pointer()) {
+            Ok(value) => cache.store(sensor.name(), value),
+            Err(error) => log::warn!(
+                "Error parsing message of sensor {} on {}: {} {}",
+                sensor.name(),
+                topic,
+                error,
+                payload
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::time::Instant;
+    use tempfile::TempDir;
+
+    /// Packets of a client the stand-in broker answers.
+    #[derive(Debug, PartialEq)]
+    enum Seen {
+        Connect,
+        Subscribe(String),
+        Unsubscribe(String),
+    }
+
+    /// A message to publish on a topic, `None` to drop the connection.
+    type Message = Option<(String, String)>;
+
+    /// Starts a minimal MQTT 3.1.1 broker for one client at a time and
+    /// returns its port. It reports the packets of the client on the
+    /// receiver and handles the messages sent to it.
+    fn broker() -> (u16, Receiver<Seen>, Sender<Message>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (seen, seen_receiver) = mpsc::channel();
+        let (publish_sender, publish) = mpsc::channel::<Message>();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                stream
+                    .set_read_timeout(Some(Duration::from_millis(20)))
+                    .unwrap();
+
+                loop {
+                    let mut packet_type = [0u8; 1];
+                    match stream.read(&mut packet_type) {
+                        Ok(0) => break,
+                        Ok(_) => answer(&mut stream, packet_type[0], &seen),
+                        Err(error)
+                            if matches!(
+                                error.kind(),
+                                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                            ) => {}
+                        Err(_) => break,
+                    }
+
+                    match publish.try_recv() {
+                        Ok(Some((topic, payload))) => {
+                            let mut packet = (topic.len() as u16).to_be_bytes().to_vec();
+                            packet.extend_from_slice(topic.as_bytes());
+                            packet.extend_from_slice(payload.as_bytes());
+                            send(&mut stream, 0x30, &packet);
+                        }
+                        Ok(None) => break,
+                        Err(_) => {}
+                    }
+                }
+            }
+        });
+
+        (port, seen_receiver, publish_sender)
+    }
+
+    fn answer(stream: &mut TcpStream, packet_type: u8, seen: &Sender<Seen>) {
+        let mut length = 0;
+        let mut shift = 0;
+        loop {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).unwrap();
+            length |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut packet = vec![0u8; length];
+        stream.read_exact(&mut packet).unwrap();
+
+        // topic filters follow the packet id, each prefixed by its length
+        let topic = || {
+            let length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+            String::from_utf8_lossy(&packet[4..4 + length]).into_owned()
+        };
+
+        match packet_type >> 4 {
+            1 => {
+                seen.send(Seen::Connect).unwrap();
+                send(stream, 0x20, &[0, 0]);
+            }
+            8 => {
+                seen.send(Seen::Subscribe(topic())).unwrap();
+                send(stream, 0x90, &[packet[0], packet[1], 0]);
+            }
+            10 => {
+                seen.send(Seen::Unsubscribe(topic())).unwrap();
+                send(stream, 0xb0, &packet[0..2]);
+            }
+            12 => send(stream, 0xd0, &[]),
+            _ => {}
+        }
+    }
+
+    fn send(stream: &mut TcpStream, packet_type: u8, packet: &[u8]) {
+        let mut bytes = vec![packet_type, packet.len() as u8];
+        bytes.extend_from_slice(packet);
+        stream.write_all(&bytes).unwrap();
+    }
+
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "Timed out");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn mqtt_sensor(name: &str, topic: &str) -> String {
+        format!(
+            "[[sensors]]\nname = \"{}\"\nkind = \"mqtt\"\ntopic = \"{}\"\n",
+            name, topic
+        )
+    }
+
+    #[test]
+    fn follows_sensor_topics_and_resubscribes_after_reconnect() {
+        let (port, seen, publish) = broker();
+        // subscriptions may be sent twice around the connection
+        let expect = |expected: Seen| loop {
+            let packet = seen.recv_timeout(Duration::from_secs(10)).unwrap();
+            if packet == expected {
+                break;
+            }
+            assert!(matches!(packet, Seen::Subscribe(_)), "{:?}", packet);
+        };
+        let message = |topic: &str, payload: &str| {
+            publish
+                .send(Some((topic.to_owned(), payload.to_owned())))
+                .unwrap()
+        };
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("Sensor.toml");
+        write(&path, mqtt_sensor("attic", "attic/temperature")).unwrap();
+        let sensors = Arc::new(SensorConfigStore::load(path.to_str().unwrap()).unwrap());
+        let cache = Arc::new(LastValueCache::default());
+        let config: MqttConfig =
+            toml::from_str(&format!("host = \"127.0.0.1\"\nport = {}", port)).unwrap();
+        let max_age = Duration::from_secs(60);
+
+        spawn(&config, sensors.clone(), cache.clone());
+
+        expect(Seen::Connect);
+        expect(Seen::Subscribe(String::from("attic/temperature")));
+        message("attic/temperature", "14.5");
+        wait_for(|| cache.recent("attic", max_age) == Some(14.5));
+
+        let cellar = SensorConfig::parse(&mqtt_sensor("cellar", "cellar/temperature")).unwrap();
+        sensors.add(cellar.sensors()[0].clone()).unwrap();
+        sensors.remove("attic").unwrap();
+
+        expect(Seen::Subscribe(String::from("cellar/temperature")));
+        expect(Seen::Unsubscribe(String::from("attic/temperature")));
+
+        // the broker forgets subscriptions of the lost connection
+        publish.send(None).unwrap();
+
+        expect(Seen::Connect);
+        expect(Seen::Subscribe(String::from("cellar/temperature")));
+        message("cellar/temperature", "9.5");
+        wait_for(|| cache.recent("cellar", max_age) == Some(9.5));
+    }
+
+    #[test]
+    fn stores_values_of_messages_for_sensors_of_their_topic() {
+        let config = SensorConfig::parse(
+            r#"
+            [[sensors]]
+            name = "living_room"
+            kind = "mqtt"
+            topic = "zigbee2mqtt/living_room"
+            json_pointer = "/temperature"
+
+            [[sensors]]
+            name = "attic"
+            kind = "mqtt"
+            topic = "attic/temperature"
+        "#,
+        )
+        .unwrap();
+        let cache = LastValueCache::default();
+        let max_age = Duration::from_secs(60);
+
+        receive(
+            &config,
+            &cache,
+            "zigbee2mqtt/living_room",
+            r#"{"battery": 97, "humidity": 48.2, "temperature": 21.3}"#,
+        );
+        receive(&config, &cache, "attic/temperature", "14.5");
+        receive(&config, &cache, "attic/temperature", "unavailable");
+
+        assert_eq!(cache.recent("living_room", max_age), Some(21.3));
+        assert_eq!(cache.recent("attic", max_age), Some(14.5));
+    }
+}
@@ -1,61 +1,163 @@
-use crate::database::{Database, DatabaseInitError};
+use crate::audit::{AuditAction, AuditEntry};
+use crate::database::DatabaseAccessError;
+use crate::derived_sensor;
+use crate::http_sensor::LastValueCache;
+use crate::sample_filter::SampleFilter;
+use crate::sensor_config::{SensorConfig, SensorConfigStore};
+use crate::storage::Storage;
 use crate::temperature_reader::TemperatureReader;
-use crate::temperature_recorder::{RecorderConfig, TemperaturesByTime};
+use crate::temperature_recorder::{now_millis, RecorderConfig, TemperaturesByTime};
 
+use chrono_tz::Tz;
 use clokwerk::{ScheduleHandle, Scheduler, TimeUnits};
-use std::time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Readings the sample filter is restored from on start, enough for the
+/// median window of any sensor at the usual intervals.
+const FILTER_HISTORY_MILLIS: u64 = 15 * 60 * 1000;
 
 pub struct RecorderScheduler {
+    db: Arc<dyn Storage>,
+    sensors: Arc<SensorConfigStore>,
+    cache: Arc<LastValueCache>,
+    timezone: Tz,
+    /// Kept across restarts of the scheduler when the config is saved
+    filter: Arc<Mutex<SampleFilter>>,
+    sinks: Vec<Arc<Mutex<dyn TemperatureSink>>>,
     thread: Option<ScheduleHandle>,
 }
 
-#[derive(Debug)]
-pub enum RecorderSchedulerError {
-    Database(DatabaseInitError),
-    Date(SystemTimeError),
+/// Receives the accepted temperatures of every tick, e.g. to pass them on
+/// to other systems. Called on the thread of the tick, after the readings
+/// are saved, so sinks must not wait on the network.
+pub trait TemperatureSink: Send {
+    fn send(&mut self, sensor_config: &SensorConfig, temperatures: &TemperaturesByTime);
 }
 
 impl RecorderScheduler {
-    pub fn new() -> Self {
-        Self { thread: None }
+    pub fn new(
+        db: Arc<dyn Storage>,
+        sensors: Arc<SensorConfigStore>,
+        cache: Arc<LastValueCache>,
+        timezone: Tz,
+    ) -> Self {
+        let filter = Arc::new(Mutex::new(Self::restore_filter(db.as_ref())));
+
+        Self {
+            db,
+            sensors,
+            cache,
+            timezone,
+            filter,
+            sinks: vec![],
+            thread: None,
+        }
     }
 
-    pub fn start(&mut self, config: &RecorderConfig) -> Result<(), RecorderSchedulerError> {
+    fn restore_filter(db: &dyn Storage) -> SampleFilter {
+        let recent = now_millis()
+            .map_err(DatabaseAccessError::Date)
+            .and_then(|now| db.load_temperatures_since(now.saturating_sub(FILTER_HISTORY_MILLIS)));
+
+        match recent {
+            Ok(recent) => SampleFilter::restore(&recent),
+            Err(error) => {
+                log::error!("Error restoring sample filter {:?}", error);
+                SampleFilter::new()
+            }
+        }
+    }
+
+    pub fn add_sink(&mut self, sink: Arc<Mutex<dyn TemperatureSink>>) {
+        self.sinks.push(sink);
+    }
+
+    pub fn start(&mut self, config: &RecorderConfig) {
         let interval = config.interval_seconds;
-        let db = Database::new().map_err(RecorderSchedulerError::Database)?;
+        let db = self.db.clone();
+        let sensors = self.sensors.clone();
+        let cache = self.cache.clone();
+        let sinks = self.sinks.clone();
+        let timezone = self.timezone;
+        let filter = self.filter.clone();
 
         let mut scheduler = Scheduler::new();
         scheduler.every(interval.seconds()).run(move || {
-            let reader = TemperatureReader::new();
-            let date;
-            let date_res = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .map(|d| d.as_millis());
-
-            // TODO make this more pretty
-            if date_res.is_err() {
-                log::error!("Error reading time");
-                return;
-            } else {
-                date = date_res.unwrap() as u64;
+            if let Some(reloaded) = sensors.reload_if_changed() {
+                audit_reload(db.as_ref(), &reloaded);
             }
+            let sensor_config = sensors.current();
+            let reader = TemperatureReader::new(sensor_config.clone(), cache.clone());
+            let date = match now_millis() {
+                Ok(date) => date,
+                Err(error) => {
+                    log::error!("Error reading time {:?}", error);
+                    return;
+                }
+            };
 
             match reader.read() {
                 Ok(temperatures) => {
-                    let temperatures_by_time = TemperaturesByTime::new(date, temperatures);
+                    let (temperatures_by_time, rejected) = {
+                        let mut filter = match filter.lock() {
+                            Ok(filter) => filter,
+                            Err(poisoned) => poisoned.into_inner(),
+                        };
+                        let (accepted, mut rejected) = filter
+                            .apply(&sensor_config, TemperaturesByTime::new(date, temperatures));
+
+                        // derived sensors only see accepted readings and are
+                        // checked by the filter like all others, before later
+                        // derived sensors use them
+                        let mut temperatures = accepted.temperatures();
+                        let derived =
+                            derived_sensor::derive(&sensor_config, &temperatures, |derived| {
+                                match filter.accept(&sensor_config, date, derived) {
+                                    Ok(()) => true,
+                                    Err(rejection) => {
+                                        rejected.push(rejection);
+                                        false
+                                    }
+                                }
+                            });
+                        temperatures.extend(derived);
+
+                        (TemperaturesByTime::new(date, temperatures), rejected)
+                    };
+
+                    if !rejected.is_empty() {
+                        if let Err(error) = db.save_rejected_temperatures(&rejected) {
+                            log::error!("Error saving rejected temperatures {:?}", error);
+                        }
+                    }
 
                     log::debug!("Successfully read sensors: {:?}", temperatures_by_time);
 
-                    if let Err(error) = db.save_temperatures(temperatures_by_time) {
+                    // saved first, so a slow or failing sink never costs a reading
+                    if let Err(error) = db.save_temperatures(temperatures_by_time.clone()) {
                         log::error!("Error saving temperatures to database {:?}", error);
                     } else {
                         log::debug!("Saved temperatures to database");
                     }
+
+                    for sink in &sinks {
+                        match sink.lock() {
+                            Ok(mut sink) => sink.send(&sensor_config, &temperatures_by_time),
+                            Err(error) => log::error!("Error locking temperature sink {}", error),
+                        }
+                    }
                 }
                 Err(error) => log::error!("Error reading sensors {:?}", error),
             }
 
-            if let Err(error) = db.delete_old_temperatures() {
+            if let Err(error) = db.update_rollups() {
+                log::error!("Error updating temperature rollups {:?}", error);
+            } else {
+                log::debug!("Updated temperature rollups");
+            }
+
+            if let Err(error) = db.delete_old_temperatures(&timezone) {
                 log::error!("Error deleting old temperatures from database {:?}", error);
             } else {
                 log::debug!("Deleted old temperatures from database");
@@ -65,11 +167,26 @@ impl RecorderScheduler {
         let thread = scheduler.watch_thread(Duration::from_millis(100));
 
         self.thread = Some(thread);
-
-        Ok(())
     }
 
     pub fn stop(&mut self) {
         self.thread = None;
     }
 }
+
+/// Records a sensor configuration picked up from a changed file, which has
+/// no client.
+fn audit_reload(db: &dyn Storage, sensor_config: &SensorConfig) {
+    let details = serde_json::to_value(sensor_config).unwrap_or(serde_json::Value::Null);
+    let entry =
+        now_millis().map(|date| AuditEntry::new(date, AuditAction::SensorsReloaded, None, details));
+
+    match entry {
+        Ok(entry) => {
+            if let Err(error) = db.save_audit_entry(&entry) {
+                log::error!("Error saving audit entry {:?}: {:?}", entry, error);
+            }
+        }
+        Err(error) => log::error!("Error reading time {:?}", error),
+    }
+}
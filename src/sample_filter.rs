@@ -0,0 +1,290 @@
+use crate::local_time::iso_date;
+use crate::sensor_config::{Sensor, SensorConfig};
+use crate::temperature_recorder::{Temperature, TemperaturesByTime};
+
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+/// Rejects implausible readings and spikes before they are stored.
+///
+/// Readings outside the plausible range of their sensor are rejected, as
+/// are readings which changed faster than `max_rate_per_minute` since the
+/// median of the last `median_window` accepted readings. Accepted readings
+/// are stored as read.
+#[derive(Default)]
+pub struct SampleFilter {
+    history: HashMap<String, VecDeque<(u64, f32)>>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RejectedTemperature {
+    name: String,
+    value: f32,
+    date: u64,
+    /// `date` in the configured timezone, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_iso: Option<String>,
+    reason: RejectReason,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    BelowMinimum,
+    AboveMaximum,
+    RateOfChange,
+}
+
+impl RejectedTemperature {
+    pub fn new(name: String, value: f32, date: u64, reason: RejectReason) -> Self {
+        Self {
+            name,
+            value,
+            date,
+            date_iso: None,
+            reason,
+        }
+    }
+
+    pub fn with_iso_date(self, timezone: &Tz) -> Self {
+        Self {
+            date_iso: Some(iso_date(self.date, timezone)),
+            ..self
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    pub fn date(&self) -> u64 {
+        self.date
+    }
+
+    pub fn reason(&self) -> RejectReason {
+        self.reason
+    }
+}
+
+impl RejectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::BelowMinimum => "below_minimum",
+            RejectReason::AboveMaximum => "above_maximum",
+            RejectReason::RateOfChange => "rate_of_change",
+        }
+    }
+
+    pub fn parse(reason: &str) -> Option<Self> {
+        match reason {
+            "below_minimum" => Some(RejectReason::BelowMinimum),
+            "above_maximum" => Some(RejectReason::AboveMaximum),
+            "rate_of_change" => Some(RejectReason::RateOfChange),
+            _ => None,
+        }
+    }
+}
+
+impl SampleFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A filter continuing from recently stored readings, e.g. after a
+    /// restart, so the first readings are checked against them as well.
+    pub fn restore(recent: &[TemperaturesByTime]) -> Self {
+        let mut filter = Self::new();
+
+        for temperatures_by_time in recent {
+            for temperature in temperatures_by_time.temperatures() {
+                filter
+                    .history
+                    .entry(temperature.name())
+                    .or_default()
+                    .push_back((temperatures_by_time.date(), temperature.value()));
+            }
+        }
+
+        filter
+    }
+
+    pub fn apply(
+        &mut self,
+        sensor_config: &SensorConfig,
+        temperatures_by_time: TemperaturesByTime,
+    ) -> (TemperaturesByTime, Vec<RejectedTemperature>) {
+        let date = temperatures_by_time.date();
+        let mut accepted = vec![];
+        let mut rejected = vec![];
+
+        for temperature in temperatures_by_time.temperatures() {
+            match self.accept(sensor_config, date, &temperature) {
+                Ok(()) => accepted.push(temperature),
+                Err(rejection) => rejected.push(rejection),
+            }
+        }
+
+        (TemperaturesByTime::new(date, accepted), rejected)
+    }
+
+    /// Checks a single reading taken at `date`, readings of unknown sensors
+    /// are accepted.
+    pub fn accept(
+        &mut self,
+        sensor_config: &SensorConfig,
+        date: u64,
+        temperature: &Temperature,
+    ) -> Result<(), RejectedTemperature> {
+        let sensor = match sensor_config.sensor(&temperature.name()) {
+            Some(sensor) => sensor,
+            None => return Ok(()),
+        };
+
+        self.check(sensor, date, temperature.value())
+            .map_err(|reason| {
+                log::warn!(
+                    "Rejected temperature {} of sensor {}: {:?}",
+                    temperature.value(),
+                    temperature.name(),
+                    reason
+                );
+                RejectedTemperature::new(temperature.name(), temperature.value(), date, reason)
+            })
+    }
+
+    fn check(&mut self, sensor: &Sensor, date: u64, value: f32) -> Result<(), RejectReason> {
+        let metadata = sensor.metadata();
+        if metadata.min().is_some_and(|min| value < min) {
+            return Err(RejectReason::BelowMinimum);
+        }
+        if metadata.max().is_some_and(|max| value > max) {
+            return Err(RejectReason::AboveMaximum);
+        }
+
+        let filter = sensor.filter();
+        let window = self.history.entry(sensor.name().to_owned()).or_default();
+        while window.len() > filter.median_window() {
+            window.pop_front();
+        }
+
+        if let (Some(max_rate), Some((median_date, median_value))) =
+            (filter.max_rate_per_minute(), median(window))
+        {
+            let minutes = date.saturating_sub(median_date) as f32 / 60_000.0;
+            if (value - median_value).abs() > max_rate * minutes {
+                return Err(RejectReason::RateOfChange);
+            }
+        }
+
+        // rejected readings must not shift the median of later ones
+        window.push_back((date, value));
+        if window.len() > filter.median_window() {
+            window.pop_front();
+        }
+
+        Ok(())
+    }
+}
+
+/// The accepted reading with the median value, the lower one of the two in
+/// the middle for an even number, so its date is known.
+fn median(window: &VecDeque<(u64, f32)>) -> Option<(u64, f32)> {
+    let mut sorted: Vec<(u64, f32)> = window.iter().copied().collect();
+    sorted.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+
+    sorted.get(sorted.len().saturating_sub(1) / 2).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temperature_recorder::Temperature;
+
+    fn reading(date: u64, value: f32) -> TemperaturesByTime {
+        TemperaturesByTime::new(date, vec![Temperature::new(String::from("flow"), value)])
+    }
+
+    fn config(options: &str) -> SensorConfig {
+        SensorConfig::parse(&format!(
+            "[[sensors]]\nname = \"flow\"\npath = \"/a\"\n{}",
+            options
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn rejects_values_outside_plausible_range() {
+        let config = config("min = -40.0\nmax = 84.0");
+        let mut filter = SampleFilter::new();
+
+        let (accepted, rejected) = filter.apply(&config, reading(0, -127.0));
+        assert!(accepted.temperatures().is_empty());
+        assert_eq!(rejected[0].reason(), RejectReason::BelowMinimum);
+
+        let (accepted, rejected) = filter.apply(&config, reading(15_000, 85.0));
+        assert!(accepted.temperatures().is_empty());
+        assert_eq!(rejected[0].reason(), RejectReason::AboveMaximum);
+    }
+
+    #[test]
+    fn rejects_spikes_faster_than_max_rate() {
+        let config = config("max_rate_per_minute = 4.0");
+        let mut filter = SampleFilter::new();
+
+        filter.apply(&config, reading(0, 50.0));
+        let (accepted, _) = filter.apply(&config, reading(15_000, 50.5));
+        assert_eq!(accepted.temperatures()[0].value(), 50.5);
+
+        let (accepted, rejected) = filter.apply(&config, reading(30_000, 70.0));
+        assert!(accepted.temperatures().is_empty());
+        assert_eq!(rejected[0].reason(), RejectReason::RateOfChange);
+    }
+
+    #[test]
+    fn rejected_spikes_do_not_affect_later_readings() {
+        let config = config("max_rate_per_minute = 4.0");
+        let mut filter = SampleFilter::new();
+
+        filter.apply(&config, reading(0, 50.0));
+        let (_, rejected) = filter.apply(&config, reading(15_000, 85.0));
+        assert_eq!(rejected.len(), 1);
+
+        // checked against 50.0 accepted before the spike, not against 85.0
+        let (accepted, rejected) = filter.apply(&config, reading(30_000, 51.0));
+        assert!(rejected.is_empty());
+        assert_eq!(accepted.temperatures()[0].value(), 51.0);
+    }
+
+    #[test]
+    fn checks_rate_against_median_and_stores_raw_readings() {
+        let config = config("max_rate_per_minute = 4.0\nmedian_window = 3");
+        let mut filter = SampleFilter::new();
+
+        filter.apply(&config, reading(0, 50.0));
+        filter.apply(&config, reading(15_000, 50.5));
+        // stored as read, not replaced by the median
+        let (accepted, _) = filter.apply(&config, reading(30_000, 52.0));
+        assert_eq!(accepted.temperatures()[0].value(), 52.0);
+
+        // 2.1 degrees above the median 50.5 of 30 seconds ago is too fast
+        let (_, rejected) = filter.apply(&config, reading(45_000, 52.6));
+        assert_eq!(rejected[0].reason(), RejectReason::RateOfChange);
+
+        let (accepted, _) = filter.apply(&config, reading(45_000, 51.9));
+        assert_eq!(accepted.temperatures()[0].value(), 51.9);
+    }
+
+    #[test]
+    fn restored_filter_checks_against_stored_readings() {
+        let config = config("max_rate_per_minute = 4.0");
+        let mut filter = SampleFilter::restore(&[reading(0, 50.0), reading(15_000, 50.5)]);
+
+        let (_, rejected) = filter.apply(&config, reading(30_000, 70.0));
+        assert_eq!(rejected[0].reason(), RejectReason::RateOfChange);
+    }
+}
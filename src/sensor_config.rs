@@ -0,0 +1,1106 @@
+use crate::burner_cycles::BurnerConfig;
+use crate::derived_sensor::Expression;
+use crate::tank_energy::TankConfig;
+use crate::temperature_recorder::MAX_PRECISION;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{metadata, read_to_string, rename, write};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Table};
+
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+
+const DEFAULT_CACHE_SECONDS: u64 = 300;
+
+/// Sensors are read within a scheduler tick, so slow sources must not take
+/// longer than the shortest sensible interval.
+const MAX_TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SensorConfig {
+    sensors: Vec<Sensor>,
+    /// Hot water tank measured by some of the sensors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tank: Option<TankConfig>,
+    /// How burner cycles are detected, they are not if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    burner: Option<BurnerConfig>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Sensor {
+    name: String,
+    #[serde(default, skip_serializing_if = "SensorKind::is_file")]
+    kind: SensorKind,
+    /// File containing millidegrees, for `file` sensors
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    path: String,
+    /// Device name of `hwmon` sensors or zone type of `thermal` sensors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<String>,
+    /// Label of the `hwmon` input, the first input is used if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    /// Program and arguments of `command` sensors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<Vec<String>>,
+    /// URL of `http` sensors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    /// Host and port of `modbus` sensors, e.g. `192.168.1.20:502`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_id: Option<u8>,
+    /// Address of the first holding register of the value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    register: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data_type: Option<ModbusDataType>,
+    /// Topic `mqtt` sensors receive their values on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    /// Expression of `derived` sensors over other sensors, e.g. `flow - return`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expression: Option<String>,
+    /// `expression` parsed once when the configuration is loaded
+    #[serde(skip)]
+    parsed_expression: Option<Expression>,
+    /// Factor the raw register value is multiplied with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scale: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout_ms: Option<u64>,
+    /// How long the last value of an `http` or `mqtt` sensor is used while
+    /// no new one arrives
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_seconds: Option<u64>,
+    /// How to read the value from the output of a sensor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<ValueFormat>,
+    /// JSON pointer to the value if `format` is `json`, e.g. `/sensor/temperature`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_pointer: Option<String>,
+    /// Decimal places to store, e.g. to drop noise below the sensor resolution
+    #[serde(skip_serializing_if = "Option::is_none")]
+    precision: Option<u32>,
+    /// Calibration offset added to every reading
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<f32>,
+    #[serde(flatten)]
+    metadata: SensorMetadata,
+    #[serde(flatten)]
+    filter: SensorFilter,
+}
+
+/// Where the readings of a sensor come from.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorKind {
+    /// A file containing millidegrees, e.g. a 1-Wire sensor
+    #[default]
+    File,
+    /// A `/sys/class/hwmon` device, found by its name and input label
+    Hwmon,
+    /// A `/sys/class/thermal` zone, found by its type
+    Thermal,
+    /// A program printing the temperature
+    Command,
+    /// A document fetched by HTTP GET
+    Http,
+    /// Holding registers of a Modbus TCP device
+    Modbus,
+    /// Messages published to an MQTT broker
+    Mqtt,
+    /// Computed from the values of other sensors
+    Derived,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModbusDataType {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueFormat {
+    /// Degrees as decimal number
+    Plain,
+    /// Integer millidegrees
+    Millidegrees,
+    /// A number or numeric string at `json_pointer` of a JSON document
+    Json,
+}
+
+impl SensorKind {
+    fn is_file(&self) -> bool {
+        *self == SensorKind::File
+    }
+
+    /// Whether reading may take long, e.g. because it waits for a process.
+    pub fn may_block(&self) -> bool {
+        matches!(
+            self,
+            SensorKind::Command | SensorKind::Http | SensorKind::Modbus
+        )
+    }
+
+    /// Whether values arrive by themselves instead of being read on demand.
+    pub fn is_pushed(&self) -> bool {
+        *self == SensorKind::Mqtt
+    }
+
+    pub fn is_derived(&self) -> bool {
+        *self == SensorKind::Derived
+    }
+
+    /// Whether values are read from the sensor on demand.
+    pub fn is_read(&self) -> bool {
+        !self.is_pushed() && !self.is_derived()
+    }
+
+    /// How values are read from the output of sensors not setting `format`.
+    pub fn default_format(&self) -> ValueFormat {
+        match self {
+            SensorKind::Command => ValueFormat::Plain,
+            SensorKind::Http => ValueFormat::Json,
+            _ => ValueFormat::Millidegrees,
+        }
+    }
+}
+
+/// Optional description of a sensor for displaying and interpreting its values.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SensorMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<SensorRole>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    colour: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sort_order: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit: Option<String>,
+    /// Lowest value the sensor can plausibly report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<f32>,
+    /// Highest value the sensor can plausibly report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<f32>,
+}
+
+/// Spike rejection applied to readings before they are stored, in addition
+/// to the plausible range of the sensor metadata.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SensorFilter {
+    /// Largest plausible change in degrees per minute
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_rate_per_minute: Option<f32>,
+    /// Number of accepted readings whose median the rate is checked against
+    #[serde(skip_serializing_if = "Option::is_none")]
+    median_window: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorRole {
+    Flow,
+    Return,
+    TankTop,
+    TankMiddle,
+    TankBottom,
+    Outdoor,
+    Flue,
+    Room,
+    Other,
+}
+
+#[derive(Debug)]
+pub enum SensorConfigError {
+    Read(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+    UnknownSensor(String),
+    Edit(toml_edit::TomlError),
+    Serialize(toml_edit::ser::Error),
+    Write(std::io::Error),
+}
+
+impl SensorConfig {
+    pub fn parse(content: &str) -> Result<Self, SensorConfigError> {
+        let mut sensor_config: SensorConfig =
+            toml::from_str(content).map_err(SensorConfigError::Parse)?;
+
+        for sensor in sensor_config.sensors.iter_mut() {
+            if sensor.kind.is_derived() {
+                let expression = Expression::parse(sensor.expression()).map_err(|error| {
+                    SensorConfigError::Invalid(format!(
+                        "Expression of sensor {} is invalid: {}",
+                        sensor.name, error
+                    ))
+                })?;
+                sensor.parsed_expression = Some(expression);
+            }
+        }
+
+        sensor_config.validate()?;
+
+        Ok(sensor_config)
+    }
+
+    pub fn read(path: &str) -> Result<Self, SensorConfigError> {
+        let content = read_to_string(path).map_err(SensorConfigError::Read)?;
+        Self::parse(&content)
+    }
+
+    pub fn sensors(&self) -> &[Sensor] {
+        &self.sensors
+    }
+
+    pub fn sensor(&self, name: &str) -> Option<&Sensor> {
+        self.sensors.iter().find(|sensor| sensor.name == name)
+    }
+
+    pub fn tank(&self) -> Option<&TankConfig> {
+        self.tank.as_ref()
+    }
+
+    pub fn burner(&self) -> Option<&BurnerConfig> {
+        self.burner.as_ref()
+    }
+
+    fn validate(&self) -> Result<(), SensorConfigError> {
+        let mut names = HashSet::new();
+
+        for sensor in &self.sensors {
+            if sensor.name.trim().is_empty() {
+                return Err(SensorConfigError::Invalid(String::from(
+                    "Sensor name must not be empty",
+                )));
+            }
+
+            if !names.insert(sensor.name.as_str()) {
+                return Err(SensorConfigError::Invalid(format!(
+                    "Sensor name {} is used more than once",
+                    sensor.name
+                )));
+            }
+
+            match sensor.kind {
+                SensorKind::File if sensor.path.trim().is_empty() => {
+                    return Err(SensorConfigError::Invalid(format!(
+                        "Path of sensor {} must not be empty",
+                        sensor.name
+                    )));
+                }
+                SensorKind::Hwmon | SensorKind::Thermal
+                    if sensor.device.as_deref().is_none_or(|d| d.trim().is_empty()) =>
+                {
+                    return Err(SensorConfigError::Invalid(format!(
+                        "Device of sensor {} must not be empty",
+                        sensor.name
+                    )));
+                }
+                SensorKind::Command
+                    if sensor
+                        .command
+                        .as_ref()
+                        .is_none_or(|command| command.is_empty()) =>
+                {
+                    return Err(SensorConfigError::Invalid(format!(
+                        "Command of sensor {} must not be empty",
+                        sensor.name
+                    )));
+                }
+                SensorKind::Http
+                    if sensor
+                        .url
+                        .as_deref()
+                        .is_none_or(|url| url.trim().is_empty()) =>
+                {
+                    return Err(SensorConfigError::Invalid(format!(
+                        "URL of sensor {} must not be empty",
+                        sensor.name
+                    )));
+                }
+                // ureq is built without TLS to keep the binary small
+                SensorKind::Http if !sensor.url().starts_with("http://") => {
+                    return Err(SensorConfigError::Invalid(format!(
+                        "URL of sensor {} must start with http://, https is not supported",
+                        sensor.name
+                    )));
+                }
+                SensorKind::Modbus
+                    if sensor
+                        .address
+                        .as_deref()
+                        .is_none_or(|a| a.trim().is_empty())
+                        || sensor.register.is_none() =>
+                {
+                    return Err(SensorConfigError::Invalid(format!(
+                        "Address and register of sensor {} must be set",
+                        sensor.name
+                    )));
+                }
+                SensorKind::Mqtt
+                    if sensor
+                        .topic
+                        .as_deref()
+                        .is_none_or(|topic| topic.trim().is_empty()) =>
+                {
+                    return Err(SensorConfigError::Invalid(format!(
+                        "Topic of sensor {} must not be empty",
+                        sensor.name
+                    )));
+                }
+                _ => {}
+            }
+
+            let format = sensor.format.unwrap_or(sensor.kind.default_format());
+            if format == ValueFormat::Json && sensor.json_pointer.is_none() {
+                return Err(SensorConfigError::Invalid(format!(
+                    "JSON pointer of sensor {} must be set for the json format",
+                    sensor.name
+                )));
+            }
+
+            if sensor
+                .timeout_ms
+                .is_some_and(|timeout| timeout > MAX_TIMEOUT_MS)
+            {
+                return Err(SensorConfigError::Invalid(format!(
+                    "Timeout of sensor {} must be at most {} ms",
+                    sensor.name, MAX_TIMEOUT_MS
+                )));
+            }
+
+            if sensor
+                .precision
+                .is_some_and(|precision| precision > MAX_PRECISION)
+            {
+                return Err(SensorConfigError::Invalid(format!(
+                    "Precision of sensor {} must be at most {}",
+                    sensor.name, MAX_PRECISION
+                )));
+            }
+
+            if sensor
+                .filter
+                .max_rate_per_minute
+                .is_some_and(|rate| rate <= 0.0)
+            {
+                return Err(SensorConfigError::Invalid(format!(
+                    "Maximum rate of sensor {} must be positive",
+                    sensor.name
+                )));
+            }
+
+            if sensor.filter.median_window == Some(0) {
+                return Err(SensorConfigError::Invalid(format!(
+                    "Median window of sensor {} must not be empty",
+                    sensor.name
+                )));
+            }
+
+            if sensor.filter.median_window.is_some() && sensor.filter.max_rate_per_minute.is_none()
+            {
+                return Err(SensorConfigError::Invalid(format!(
+                    "Median window of sensor {} needs a maximum rate to check against",
+                    sensor.name
+                )));
+            }
+
+            if let (Some(min), Some(max)) = (sensor.metadata.min, sensor.metadata.max) {
+                if min >= max {
+                    return Err(SensorConfigError::Invalid(format!(
+                        "Minimum of sensor {} must be lower than its maximum",
+                        sensor.name
+                    )));
+                }
+            }
+        }
+
+        // derived sensors are evaluated in order, after all others were read
+        for (index, sensor) in self.sensors.iter().enumerate() {
+            let expression = match &sensor.parsed_expression {
+                Some(expression) => expression,
+                None => continue,
+            };
+
+            for name in expression.sensors() {
+                match self.sensors.iter().position(|other| other.name == name) {
+                    None => {
+                        return Err(SensorConfigError::Invalid(format!(
+                            "Sensor {} of the expression of sensor {} is not configured",
+                            name, sensor.name
+                        )));
+                    }
+                    Some(other) if self.sensors[other].kind.is_derived() && other >= index => {
+                        return Err(SensorConfigError::Invalid(format!(
+                            "Derived sensor {} can only refer to derived sensors configured before it",
+                            sensor.name
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(tank) = &self.tank {
+            tank.validate().map_err(SensorConfigError::Invalid)?;
+
+            for layer in tank.layers() {
+                if !names.contains(layer.sensor()) {
+                    return Err(SensorConfigError::Invalid(format!(
+                        "Sensor {} of tank layer is not configured",
+                        layer.sensor()
+                    )));
+                }
+            }
+        }
+
+        if let Some(burner) = &self.burner {
+            burner.validate().map_err(SensorConfigError::Invalid)?;
+
+            if let Some(sensor) = burner.sensor().filter(|sensor| !names.contains(sensor)) {
+                return Err(SensorConfigError::Invalid(format!(
+                    "Sensor {} of burner is not configured",
+                    sensor
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Sensor {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> SensorKind {
+        self.kind
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn device(&self) -> Option<&str> {
+        self.device.as_deref()
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn command(&self) -> &[String] {
+        self.command.as_deref().unwrap_or_default()
+    }
+
+    pub fn url(&self) -> &str {
+        self.url.as_deref().unwrap_or_default()
+    }
+
+    pub fn address(&self) -> &str {
+        self.address.as_deref().unwrap_or_default()
+    }
+
+    pub fn unit_id(&self) -> u8 {
+        self.unit_id.unwrap_or(1)
+    }
+
+    pub fn register(&self) -> u16 {
+        self.register.unwrap_or_default()
+    }
+
+    pub fn data_type(&self) -> ModbusDataType {
+        self.data_type.unwrap_or(ModbusDataType::I16)
+    }
+
+    pub fn topic(&self) -> &str {
+        self.topic.as_deref().unwrap_or_default()
+    }
+
+    pub fn expression(&self) -> &str {
+        self.expression.as_deref().unwrap_or_default()
+    }
+
+    /// The parsed expression of `derived` sensors.
+    pub fn parsed_expression(&self) -> Option<&Expression> {
+        self.parsed_expression.as_ref()
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale.unwrap_or(1.0)
+    }
+
+    pub fn cache_duration(&self) -> Duration {
+        Duration::from_secs(self.cache_seconds.unwrap_or(DEFAULT_CACHE_SECONDS))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS))
+    }
+
+    pub fn format(&self) -> Option<ValueFormat> {
+        self.format
+    }
+
+    pub fn json_pointer(&self) -> &str {
+        self.json_pointer.as_deref().unwrap_or_default()
+    }
+
+    pub fn precision(&self) -> Option<u32> {
+        self.precision
+    }
+
+    pub fn offset(&self) -> f32 {
+        self.offset.unwrap_or(0.0)
+    }
+
+    pub fn metadata(&self) -> &SensorMetadata {
+        &self.metadata
+    }
+
+    pub fn filter(&self) -> &SensorFilter {
+        &self.filter
+    }
+}
+
+impl SensorFilter {
+    pub fn max_rate_per_minute(&self) -> Option<f32> {
+        self.max_rate_per_minute
+    }
+
+    pub fn median_window(&self) -> usize {
+        self.median_window.unwrap_or(1)
+    }
+}
+
+impl SensorMetadata {
+    pub fn role(&self) -> Option<SensorRole> {
+        self.role
+    }
+
+    pub fn sort_order(&self) -> Option<i32> {
+        self.sort_order
+    }
+
+    pub fn unit(&self) -> Option<&str> {
+        self.unit.as_deref()
+    }
+
+    pub fn min(&self) -> Option<f32> {
+        self.min
+    }
+
+    pub fn max(&self) -> Option<f32> {
+        self.max
+    }
+}
+
+/// Holds the currently active sensor configuration.
+///
+/// A new configuration only replaces the active one if it parses and
+/// validates; otherwise the previous configuration stays active and the
+/// error is kept for reporting.
+pub struct SensorConfigStore {
+    path: String,
+    state: RwLock<SensorConfigState>,
+}
+
+struct SensorConfigState {
+    config: Arc<SensorConfig>,
+    modified: Option<SystemTime>,
+    last_error: Option<String>,
+}
+
+impl SensorConfigStore {
+    pub fn load(path: &str) -> Result<Self, SensorConfigError> {
+        let modified = Self::modified(path);
+        let config = SensorConfig::read(path)?;
+
+        let state = SensorConfigState {
+            config: Arc::new(config),
+            modified,
+            last_error: None,
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            state: RwLock::new(state),
+        })
+    }
+
+    pub fn current(&self) -> Arc<SensorConfig> {
+        match self.state.read() {
+            Ok(state) => state.config.clone(),
+            Err(poisoned) => poisoned.into_inner().config.clone(),
+        }
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        match self.state.read() {
+            Ok(state) => state.last_error.clone(),
+            Err(poisoned) => poisoned.into_inner().last_error.clone(),
+        }
+    }
+
+    pub fn reload(&self) -> Result<Arc<SensorConfig>, SensorConfigError> {
+        let modified = Self::modified(&self.path);
+        let result = SensorConfig::read(&self.path);
+
+        let mut state = match self.state.write() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        state.modified = modified;
+
+        match result {
+            Ok(config) => {
+                log::info!("Reloaded sensor configuration from {}", self.path);
+                state.config = Arc::new(config);
+                state.last_error = None;
+                Ok(state.config.clone())
+            }
+            Err(error) => {
+                log::error!(
+                    "Error reloading sensor configuration, keeping previous one: {:?}",
+                    error
+                );
+                state.last_error = Some(format!("{:?}", error));
+                Err(error)
+            }
+        }
+    }
+
+    pub fn add(&self, sensor: Sensor) -> Result<Arc<SensorConfig>, SensorConfigError> {
+        let table = sensor_table(&sensor)?;
+        self.modify(|sensors| {
+            sensors.push(table);
+            Ok(())
+        })
+    }
+
+    /// The configuration `add` would make active, without saving it.
+    pub fn check_add(&self, sensor: &Sensor) -> Result<SensorConfig, SensorConfigError> {
+        let table = sensor_table(sensor)?;
+        let (_, config) = self.edit(|sensors| {
+            sensors.push(table);
+            Ok(())
+        })?;
+
+        Ok(config)
+    }
+
+    /// Replaces a sensor, keeping the comments and layout of the values
+    /// which are still set.
+    pub fn update(
+        &self,
+        name: &str,
+        sensor: Sensor,
+    ) -> Result<Arc<SensorConfig>, SensorConfigError> {
+        let table = sensor_table(&sensor)?;
+        self.modify(|sensors| replace_table(sensors, name, table))
+    }
+
+    /// The configuration `update` would make active, without saving it.
+    pub fn check_update(
+        &self,
+        name: &str,
+        sensor: &Sensor,
+    ) -> Result<SensorConfig, SensorConfigError> {
+        let table = sensor_table(sensor)?;
+        let (_, config) = self.edit(|sensors| replace_table(sensors, name, table))?;
+
+        Ok(config)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<Arc<SensorConfig>, SensorConfigError> {
+        self.modify(|sensors| {
+            let index = sensors
+                .iter()
+                .position(|s| table_name(s) == Some(name))
+                .ok_or_else(|| SensorConfigError::UnknownSensor(name.to_owned()))?;
+            sensors.remove(index);
+            Ok(())
+        })
+    }
+
+    /// Applies `change` to the sensors of the config file and, if the
+    /// result validates, writes it back and makes it active.
+    ///
+    /// The file is edited in place, so comments and the layout of
+    /// everything not changed are kept.
+    fn modify<F>(&self, change: F) -> Result<Arc<SensorConfig>, SensorConfigError>
+    where
+        F: FnOnce(&mut ArrayOfTables) -> Result<(), SensorConfigError>,
+    {
+        let mut state = match self.state.write() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let (content, config) = self.edit(change)?;
+
+        // Write to a temporary file first, so a crash never leaves a
+        // truncated config behind
+        let temporary_path = format!("{}.tmp", self.path);
+        write(&temporary_path, content).map_err(SensorConfigError::Write)?;
+        rename(&temporary_path, &self.path).map_err(SensorConfigError::Write)?;
+
+        log::info!("Saved sensor configuration to {}", self.path);
+
+        state.config = Arc::new(config);
+        state.modified = Self::modified(&self.path);
+        state.last_error = None;
+
+        Ok(state.config.clone())
+    }
+
+    /// Applies `change` to the sensors of the config file and returns the
+    /// edited file with the configuration parsed and validated from it.
+    fn edit<F>(&self, change: F) -> Result<(String, SensorConfig), SensorConfigError>
+    where
+        F: FnOnce(&mut ArrayOfTables) -> Result<(), SensorConfigError>,
+    {
+        let mut document: DocumentMut = read_to_string(&self.path)
+            .map_err(SensorConfigError::Read)?
+            .parse()
+            .map_err(SensorConfigError::Edit)?;
+        change(sensor_tables(&mut document)?)?;
+
+        let content = document.to_string();
+        let config = SensorConfig::parse(&content)?;
+
+        Ok((content, config))
+    }
+
+    /// Reloads the configuration if the file was modified since it was last
+    /// read, returning the new configuration if it replaced the active one.
+    pub fn reload_if_changed(&self) -> Option<Arc<SensorConfig>> {
+        let modified = Self::modified(&self.path);
+        let known = match self.state.read() {
+            Ok(state) => state.modified,
+            Err(poisoned) => poisoned.into_inner().modified,
+        };
+
+        if modified != known {
+            // errors are logged and kept for /health
+            self.reload().ok()
+        } else {
+            None
+        }
+    }
+
+    fn modified(path: &str) -> Option<SystemTime> {
+        metadata(path).and_then(|m| m.modified()).ok()
+    }
+}
+
+/// The `[[sensors]]` tables of a config file, sensors given as inline
+/// array are converted to tables.
+fn sensor_tables(document: &mut DocumentMut) -> Result<&mut ArrayOfTables, SensorConfigError> {
+    let sensors = document
+        .entry("sensors")
+        .or_insert(Item::ArrayOfTables(ArrayOfTables::new()));
+
+    if let Some(array) = sensors.as_array().cloned() {
+        let tables = array
+            .into_iter()
+            .filter_map(|sensor| sensor.as_inline_table().cloned())
+            .map(|sensor| sensor.into_table())
+            .collect();
+        *sensors = Item::ArrayOfTables(tables);
+    }
+
+    sensors
+        .as_array_of_tables_mut()
+        .ok_or_else(|| SensorConfigError::Invalid(String::from("sensors must be tables")))
+}
+
+fn sensor_table(sensor: &Sensor) -> Result<Table, SensorConfigError> {
+    let document = toml_edit::ser::to_document(sensor).map_err(SensorConfigError::Serialize)?;
+    Ok(document.as_table().clone())
+}
+
+fn table_name(table: &Table) -> Option<&str> {
+    table.get("name").and_then(Item::as_str)
+}
+
+/// Updates the table of the sensor `name` to `new`.
+fn replace_table(
+    sensors: &mut ArrayOfTables,
+    name: &str,
+    new: Table,
+) -> Result<(), SensorConfigError> {
+    let existing = sensors
+        .iter_mut()
+        .find(|s| table_name(s) == Some(name))
+        .ok_or_else(|| SensorConfigError::UnknownSensor(name.to_owned()))?;
+    update_table(existing, new);
+    Ok(())
+}
+
+/// Sets the values of `table` to those of `new`, keeping the comments and
+/// formatting of values which are still set.
+fn update_table(table: &mut Table, new: Table) {
+    let removed: Vec<String> = table
+        .iter()
+        .map(|(key, _)| key.to_owned())
+        .filter(|key| !new.contains_key(key))
+        .collect();
+    for key in removed {
+        table.remove(&key);
+    }
+
+    for (key, item) in new {
+        match (table.get_mut(&key).and_then(Item::as_value_mut), item) {
+            (Some(existing), Item::Value(mut value)) => {
+                *value.decor_mut() = existing.decor().clone();
+                if value.to_string() != existing.to_string() {
+                    *existing = value;
+                }
+            }
+            (_, item) => {
+                table.insert(&key, item);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    const VALID: &str = r#"
+        [[sensors]]
+        name = "flow"
+        path = "/sys/bus/w1/devices/28-1/temperature"
+    "#;
+
+    #[test]
+    fn parses_sensor_metadata() {
+        let config = SensorConfig::parse(
+            r#"
+            [[sensors]]
+            name = "tank top"
+            path = "/a"
+            role = "tank_top"
+            group = "tank"
+            sort_order = 1
+            min = 0.0
+            max = 95.0
+        "#,
+        )
+        .unwrap();
+
+        let metadata = config.sensors()[0].metadata();
+        assert_eq!(metadata.role(), Some(SensorRole::TankTop));
+        assert_eq!(metadata.sort_order(), Some(1));
+        assert_eq!(metadata.max(), Some(95.0));
+    }
+
+    #[test]
+    fn rejects_duplicate_sensor_names() {
+        let config = SensorConfig::parse(
+            r#"
+            [[sensors]]
+            name = "flow"
+            path = "/a"
+
+            [[sensors]]
+            name = "flow"
+            path = "/b"
+        "#,
+        );
+
+        assert!(matches!(config, Err(SensorConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn validates_sensors_of_derived_expressions() {
+        let config = |expression: &str| {
+            SensorConfig::parse(&format!(
+                r#"
+                [[sensors]]
+                name = "flow"
+                path = "/a"
+
+                [[sensors]]
+                name = "delta"
+                kind = "derived"
+                expression = "{}"
+
+                [[sensors]]
+                name = "return"
+                path = "/b"
+            "#,
+                expression
+            ))
+        };
+
+        assert!(config("flow - return").is_ok());
+        assert!(matches!(
+            config("flow - outdoor"),
+            Err(SensorConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            config("delta * 2"),
+            Err(SensorConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            config("flow -"),
+            Err(SensorConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn validates_http_sensors() {
+        let config = |options: &str| {
+            SensorConfig::parse(&format!(
+                "[[sensors]]\nname = \"esp32\"\nkind = \"http\"\n{}",
+                options
+            ))
+        };
+
+        assert!(config("url = \"http://esp32.local/\"\njson_pointer = \"/t\"").is_ok());
+        assert!(config("url = \"http://esp32.local/\"\nformat = \"plain\"").is_ok());
+        // json is the default format of http sensors
+        assert!(matches!(
+            config("url = \"http://esp32.local/\""),
+            Err(SensorConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            config("url = \"https://esp32.local/\"\njson_pointer = \"/t\""),
+            Err(SensorConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn writes_added_sensor_to_config_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("Sensor.toml");
+        write(&path, VALID).unwrap();
+
+        let store = SensorConfigStore::load(path.to_str().unwrap()).unwrap();
+        let sensor = Sensor {
+            name: String::from("return"),
+            kind: SensorKind::File,
+            path: String::from("/sys/bus/w1/devices/28-2/temperature"),
+            device: None,
+            label: None,
+            command: None,
+            url: None,
+            address: None,
+            unit_id: None,
+            register: None,
+            data_type: None,
+            topic: None,
+            expression: None,
+            parsed_expression: None,
+            scale: None,
+            timeout_ms: None,
+            cache_seconds: None,
+            format: None,
+            json_pointer: None,
+            precision: None,
+            offset: Some(-0.5),
+            metadata: SensorMetadata::default(),
+            filter: SensorFilter::default(),
+        };
+        store.add(sensor).unwrap();
+
+        let written = SensorConfig::read(path.to_str().unwrap()).unwrap();
+        assert_eq!(written.sensors().len(), 2);
+        assert_eq!(written.sensors()[1].offset(), -0.5);
+
+        assert!(matches!(
+            store.remove("unknown"),
+            Err(SensorConfigError::UnknownSensor(_))
+        ));
+    }
+
+    #[test]
+    fn keeps_comments_and_layout_when_editing_config_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("Sensor.toml");
+        write(
+            &path,
+            r#"# Sensors of the boiler room
+
+[[sensors]]
+name = "flow"
+# calibrated against the boiler display
+offset = -0.5
+path = "/a"
+
+[[sensors]]
+name = "return"
+path = "/b"
+"#,
+        )
+        .unwrap();
+
+        let store = SensorConfigStore::load(path.to_str().unwrap()).unwrap();
+        let mut flow = store.current().sensor("flow").unwrap().clone();
+        flow.offset = Some(-0.25);
+        flow.precision = Some(1);
+        store.update("flow", flow).unwrap();
+        let mut outdoor = store.current().sensor("return").unwrap().clone();
+        outdoor.name = String::from("outdoor");
+        store.add(outdoor).unwrap();
+        store.remove("return").unwrap();
+
+        assert_eq!(
+            read_to_string(&path).unwrap(),
+            r#"# Sensors of the boiler room
+
+[[sensors]]
+name = "flow"
+# calibrated against the boiler display
+offset = -0.25
+path = "/a"
+precision = 1
+
+[[sensors]]
+name = "outdoor"
+path = "/b"
+"#
+        );
+        assert_eq!(store.current().sensors().len(), 2);
+    }
+
+    #[test]
+    fn keeps_previous_config_when_reload_fails() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("Sensor.toml");
+        write(&path, VALID).unwrap();
+
+        let store = SensorConfigStore::load(path.to_str().unwrap()).unwrap();
+
+        write(&path, "[[sensors]\nname = ").unwrap();
+        assert!(store.reload().is_err());
+        assert_eq!(store.current().sensors()[0].name(), "flow");
+        assert!(store.last_error().is_some());
+
+        write(&path, VALID.replace("flow", "return")).unwrap();
+        store.reload().unwrap();
+        assert_eq!(store.current().sensors()[0].name(), "return");
+        assert!(store.last_error().is_none());
+    }
+}
@@ -0,0 +1,158 @@
+use crate::audit::AuditEntry;
+use crate::auth::ApiToken;
+use crate::burner_cycles::BurnerCycle;
+use crate::database::{DatabaseAccessError, DatabaseStats};
+use crate::sample_filter::RejectedTemperature;
+use crate::temperature_recorder::{RecorderConfig, TemperaturesByTime};
+
+use chrono_tz::Tz;
+
+/// Persistence of the recorder configuration and the recorded temperatures.
+///
+/// `Database` is the SQLite backed default, `MemoryStorage` keeps everything
+/// in memory for tests and the `--ephemeral` demo mode.
+pub trait Storage: Send + Sync {
+    fn load_recorder_config(&self) -> Result<RecorderConfig, DatabaseAccessError>;
+
+    /// Saves the configuration and remembers when the interval changed.
+    fn save_recorder_config(
+        &self,
+        config: RecorderConfig,
+    ) -> Result<RecorderConfig, DatabaseAccessError>;
+
+    /// Intervals the recorder used over time, oldest first.
+    fn load_interval_history(&self) -> Result<Vec<IntervalChange>, DatabaseAccessError>;
+
+    /// Saves all temperatures of one timestamp, either completely or not at all.
+    fn save_temperatures(
+        &self,
+        temperatures_by_time: TemperaturesByTime,
+    ) -> Result<(), DatabaseAccessError>;
+
+    /// Loads all temperatures since `since` from the finest resolution whose
+    /// retention still covers the requested range.
+    fn load_temperatures_since(
+        &self,
+        since: u64,
+    ) -> Result<Vec<TemperaturesByTime>, DatabaseAccessError>;
+
+    /// Like `load_temperatures_since`, only up to before `to`.
+    fn load_temperatures_between(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<TemperaturesByTime>, DatabaseAccessError>;
+
+    fn load_last_temperature(&self) -> Result<Option<TemperaturesByTime>, DatabaseAccessError>;
+
+    /// Aggregates recent raw temperatures into the rollup resolutions.
+    fn update_rollups(&self) -> Result<(), DatabaseAccessError>;
+
+    /// Deletes temperatures of local days beyond the retention of their
+    /// resolution and returns the number of deleted entries.
+    fn delete_old_temperatures(&self, timezone: &Tz) -> Result<usize, DatabaseAccessError>;
+
+    /// Keeps temperatures rejected by the `SampleFilter` for later review.
+    fn save_rejected_temperatures(
+        &self,
+        rejected: &[RejectedTemperature],
+    ) -> Result<(), DatabaseAccessError>;
+
+    fn load_rejected_temperatures_since(
+        &self,
+        since: u64,
+    ) -> Result<Vec<RejectedTemperature>, DatabaseAccessError>;
+
+    /// Aggregates the temperatures of each sensor, or only of `sensor`,
+    /// between `from` inclusive and `to` exclusive, from the finest
+    /// resolution whose retention still covers `from`.
+    fn load_range_stats(
+        &self,
+        from: u64,
+        to: u64,
+        sensor: Option<&str>,
+        threshold: Option<f32>,
+    ) -> Result<Vec<RangeStats>, DatabaseAccessError>;
+
+    fn save_burner_cycle(&self, cycle: &BurnerCycle) -> Result<(), DatabaseAccessError>;
+
+    /// Loads the burner cycles started between `from` and `to`, oldest first.
+    fn load_burner_cycles(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<BurnerCycle>, DatabaseAccessError>;
+
+    fn save_audit_entry(&self, entry: &AuditEntry) -> Result<(), DatabaseAccessError>;
+
+    /// Loads the audit entries between `from` and `to`, oldest first.
+    fn load_audit_entries(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<AuditEntry>, DatabaseAccessError>;
+
+    fn save_api_token(&self, token: &ApiToken) -> Result<(), DatabaseAccessError>;
+
+    fn load_api_tokens(&self) -> Result<Vec<ApiToken>, DatabaseAccessError>;
+
+    /// Deletes the token named `name` and returns the number of deleted tokens.
+    fn delete_api_token(&self, name: &str) -> Result<usize, DatabaseAccessError>;
+
+    /// Queues a batch to be forwarded to another system once it is reachable.
+    fn push_outbox(&self, body: &str) -> Result<(), DatabaseAccessError>;
+
+    /// Loads up to `limit` of the oldest queued batches, oldest first.
+    fn load_outbox(&self, limit: usize) -> Result<Vec<OutboxBatch>, DatabaseAccessError>;
+
+    /// Removes all queued batches up to and including `id` once forwarded.
+    fn delete_outbox(&self, id: u64) -> Result<usize, DatabaseAccessError>;
+
+    /// Removes the oldest queued batches beyond the `keep` newest ones.
+    fn trim_outbox(&self, keep: usize) -> Result<usize, DatabaseAccessError>;
+
+    fn stats(&self) -> DatabaseStats;
+
+    /// Size of the storage on disk, `None` if it is not stored on disk.
+    fn size_on_disk(&self) -> Option<u64>;
+}
+
+/// The recorder interval used from `since` on, until the next change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntervalChange {
+    pub since: u64,
+    pub interval_seconds: u32,
+}
+
+/// Recorder interval at `date` from the ascending `history`, the first one
+/// for dates before it started.
+pub fn interval_at(history: &[IntervalChange], date: u64) -> Option<u32> {
+    history
+        .iter()
+        .rev()
+        .find(|change| change.since <= date)
+        .or(history.first())
+        .map(|change| change.interval_seconds)
+}
+
+/// A batch waiting in the outbox, ids grow in the order batches were queued.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxBatch {
+    pub id: u64,
+    pub body: String,
+}
+
+/// Aggregate of one sensor over a range of time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeStats {
+    pub name: String,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// Time covered by recorded temperatures, each raw one covering the
+    /// interval used when it was recorded
+    pub covered_seconds: u64,
+    /// Time the temperature was above the threshold, 0 without threshold.
+    /// Rollup buckets count as a whole if their average is above.
+    pub above_seconds: u64,
+}
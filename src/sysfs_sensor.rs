@@ -0,0 +1,142 @@
+use std::fs::{read_dir, read_to_string};
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const HWMON_ROOT: &str = "/sys/class/hwmon";
+pub const THERMAL_ROOT: &str = "/sys/class/thermal";
+
+/// Finds the `temp*_input` file of the hwmon device with the given `name`.
+///
+/// hwmon indices are assigned at boot and may change, so the device is
+/// looked up by its name and, if given, the input by its `temp*_label`.
+pub fn resolve_hwmon(root: &Path, device: &str, label: Option<&str>) -> io::Result<PathBuf> {
+    for device_dir in find_by_attribute(root, "hwmon", "name", device)? {
+        let input = match label {
+            Some(label) => find_hwmon_input_by_label(&device_dir, label)?,
+            None => Some(device_dir.join("temp1_input")),
+        };
+
+        if let Some(input) = input.filter(|input| input.exists()) {
+            return Ok(input);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No hwmon device {} with label {:?}", device, label),
+    ))
+}
+
+/// Finds the `temp` file of the thermal zone with the given `type`.
+pub fn resolve_thermal_zone(root: &Path, zone_type: &str) -> io::Result<PathBuf> {
+    find_by_attribute(root, "thermal_zone", "type", zone_type)?
+        .into_iter()
+        .map(|zone_dir| zone_dir.join("temp"))
+        .find(|input| input.exists())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No thermal zone of type {}", zone_type),
+            )
+        })
+}
+
+fn find_by_attribute(
+    root: &Path,
+    prefix: &str,
+    attribute: &str,
+    value: &str,
+) -> io::Result<Vec<PathBuf>> {
+    let mut matches = vec![];
+
+    for entry in read_dir(root)? {
+        let path = entry?.path();
+        let is_candidate = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(prefix));
+
+        if is_candidate && read_attribute(&path.join(attribute)).as_deref() == Some(value) {
+            matches.push(path);
+        }
+    }
+
+    matches.sort();
+    Ok(matches)
+}
+
+fn find_hwmon_input_by_label(device_dir: &Path, label: &str) -> io::Result<Option<PathBuf>> {
+    for entry in read_dir(device_dir)? {
+        let path = entry?.path();
+        let file_name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) => file_name.to_owned(),
+            None => continue,
+        };
+
+        if let Some(input) = file_name
+            .strip_prefix("temp")
+            .and_then(|rest| rest.strip_suffix("_label"))
+        {
+            if read_attribute(&path).as_deref() == Some(label) {
+                return Ok(Some(device_dir.join(format!("temp{}_input", input))));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn read_attribute(path: &Path) -> Option<String> {
+    read_to_string(path)
+        .ok()
+        .map(|content| content.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, write};
+    use tempfile::TempDir;
+
+    fn hwmon_device(root: &Path, index: u32, name: &str, inputs: &[(&str, &str)]) {
+        let dir = root.join(format!("hwmon{}", index));
+        create_dir(&dir).unwrap();
+        write(dir.join("name"), format!("{}\n", name)).unwrap();
+        for (i, (label, value)) in inputs.iter().enumerate() {
+            write(dir.join(format!("temp{}_label", i + 1)), label).unwrap();
+            write(dir.join(format!("temp{}_input", i + 1)), value).unwrap();
+        }
+    }
+
+    #[test]
+    fn resolves_hwmon_input_by_device_name_and_label() {
+        let root = TempDir::new().unwrap();
+        hwmon_device(root.path(), 0, "cpu_thermal", &[("cpu", "45000")]);
+        hwmon_device(
+            root.path(),
+            1,
+            "lm75",
+            &[("ambient", "21000"), ("tank", "60000")],
+        );
+
+        let input = resolve_hwmon(root.path(), "lm75", Some("tank")).unwrap();
+        assert_eq!(input, root.path().join("hwmon1").join("temp2_input"));
+
+        let input = resolve_hwmon(root.path(), "lm75", None).unwrap();
+        assert_eq!(input, root.path().join("hwmon1").join("temp1_input"));
+
+        assert!(resolve_hwmon(root.path(), "lm75", Some("flue")).is_err());
+    }
+
+    #[test]
+    fn resolves_thermal_zone_by_type() {
+        let root = TempDir::new().unwrap();
+        let zone = root.path().join("thermal_zone3");
+        create_dir(&zone).unwrap();
+        write(zone.join("type"), "cpu-thermal\n").unwrap();
+        write(zone.join("temp"), "48000\n").unwrap();
+
+        let input = resolve_thermal_zone(root.path(), "cpu-thermal").unwrap();
+        assert_eq!(input, zone.join("temp"));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::local_time::iso_date;
+use crate::temperature_recorder::{round, TemperaturesByTime};
+
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+/// Energy needed to heat one litre of water by one kelvin
+const KWH_PER_LITRE_KELVIN: f32 = 4.186 / 3600.0;
+
+/// Hot water tank whose stored energy is derived from its layer sensors.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TankConfig {
+    volume_litres: f32,
+    /// Temperature of the cold water refilling the tank, energy is counted above it
+    #[serde(default = "TankConfig::default_cold_water_temperature")]
+    cold_water_temperature: f32,
+    /// Temperature hot water is mixed down to at the tap
+    #[serde(default = "TankConfig::default_target_temperature")]
+    target_temperature: f32,
+    /// Layers from top to bottom, each measured by one sensor
+    layers: Vec<TankLayer>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TankLayer {
+    sensor: String,
+    /// Height of the layer in any unit, layers share the volume by height
+    height: f32,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct TankEnergy {
+    /// Energy stored above the cold water temperature
+    stored_kwh: f32,
+    /// Water available at the target temperature when mixed with cold water
+    usable_litres: f32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TankEnergyByTime {
+    date: u64,
+    /// `date` in the configured timezone, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_iso: Option<String>,
+    #[serde(flatten)]
+    energy: TankEnergy,
+}
+
+impl TankConfig {
+    pub fn default_cold_water_temperature() -> f32 {
+        10.0
+    }
+
+    pub fn default_target_temperature() -> f32 {
+        40.0
+    }
+
+    pub fn layers(&self) -> &[TankLayer] {
+        &self.layers
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.volume_litres <= 0.0 {
+            return Err(String::from("Tank volume must be positive"));
+        }
+
+        if self.target_temperature <= self.cold_water_temperature {
+            return Err(String::from(
+                "Tank target temperature must be above the cold water temperature",
+            ));
+        }
+
+        if self.layers.is_empty() {
+            return Err(String::from("Tank must have at least one layer"));
+        }
+
+        if self.layers.iter().any(|layer| layer.height <= 0.0) {
+            return Err(String::from("Height of tank layers must be positive"));
+        }
+
+        Ok(())
+    }
+
+    /// Energy of the tank at one timestamp, `None` if a layer sensor has no
+    /// temperature at it.
+    pub fn energy(&self, temperatures: &TemperaturesByTime) -> Option<TankEnergy> {
+        let temperatures = temperatures.temperatures();
+        let total_height: f32 = self.layers.iter().map(|layer| layer.height).sum();
+        let mix_range = self.target_temperature - self.cold_water_temperature;
+
+        let mut stored_kwh = 0.0;
+        let mut usable_litres = 0.0;
+
+        for layer in &self.layers {
+            let temperature = temperatures
+                .iter()
+                .find(|temperature| temperature.name() == layer.sensor)?
+                .value();
+            let litres = self.volume_litres * layer.height / total_height;
+            let above_cold = (temperature - self.cold_water_temperature).max(0.0);
+
+            stored_kwh += litres * above_cold * KWH_PER_LITRE_KELVIN;
+
+            // layers below the target temperature can't be mixed up to it
+            if temperature >= self.target_temperature {
+                usable_litres += litres * above_cold / mix_range;
+            }
+        }
+
+        Some(TankEnergy {
+            stored_kwh,
+            usable_litres,
+        })
+    }
+
+    /// Energy at every timestamp all layer sensors have a temperature at.
+    pub fn energy_series(&self, temperatures: &[TemperaturesByTime]) -> Vec<TankEnergyByTime> {
+        temperatures
+            .iter()
+            .filter_map(|temperatures| {
+                self.energy(temperatures).map(|energy| TankEnergyByTime {
+                    date: temperatures.date(),
+                    date_iso: None,
+                    energy,
+                })
+            })
+            .collect()
+    }
+}
+
+impl TankLayer {
+    pub fn sensor(&self) -> &str {
+        &self.sensor
+    }
+}
+
+impl TankEnergy {
+    pub fn rounded(self, precision: u32) -> Self {
+        Self {
+            stored_kwh: round(self.stored_kwh, precision),
+            usable_litres: round(self.usable_litres, precision),
+        }
+    }
+}
+
+impl TankEnergyByTime {
+    pub fn rounded(self, precision: u32) -> Self {
+        Self {
+            energy: self.energy.rounded(precision),
+            ..self
+        }
+    }
+
+    pub fn with_iso_date(self, timezone: &Tz) -> Self {
+        Self {
+            date_iso: Some(iso_date(self.date, timezone)),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temperature_recorder::Temperature;
+
+    fn tank() -> TankConfig {
+        toml::from_str(
+            r#"
+            volume_litres = 300
+            cold_water_temperature = 10
+            target_temperature = 40
+            layers = [
+                { sensor = "tank_top", height = 1 },
+                { sensor = "tank_middle", height = 1 },
+                { sensor = "tank_bottom", height = 1 },
+            ]
+        "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn computes_stored_energy_and_usable_litres() {
+        let temperatures = TemperaturesByTime::new(
+            1000,
+            vec![
+                Temperature::new(String::from("tank_top"), 70.0),
+                Temperature::new(String::from("tank_middle"), 40.0),
+                Temperature::new(String::from("tank_bottom"), 10.0),
+            ],
+        );
+
+        let energy = tank().energy(&temperatures).unwrap().rounded(2);
+
+        // 100 l each, 60 K + 30 K + 0 K above cold water
+        assert_eq!(energy.stored_kwh, 10.47);
+        // 200 l + 100 l at 40 °C when mixed with cold water
+        assert_eq!(energy.usable_litres, 300.0);
+    }
+
+    #[test]
+    fn skips_timestamps_without_all_layers() {
+        let temperatures = vec![
+            TemperaturesByTime::new(1000, vec![Temperature::new(String::from("tank_top"), 70.0)]),
+            TemperaturesByTime::new(
+                2000,
+                vec![
+                    Temperature::new(String::from("tank_top"), 70.0),
+                    Temperature::new(String::from("tank_middle"), 70.0),
+                    Temperature::new(String::from("tank_bottom"), 70.0),
+                ],
+            ),
+        ];
+
+        let series = tank().energy_series(&temperatures);
+
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].date, 2000);
+    }
+}
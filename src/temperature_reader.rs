@@ -1,58 +1,87 @@
-use crate::temperature_recorder::Temperature;
+use crate::command_sensor::{self, CommandError};
+use crate::http_sensor::{self, HttpSensorError, LastValueCache};
+use crate::modbus_sensor::{self, ModbusError};
+use crate::sensor_config::{Sensor, SensorConfig, SensorKind, ValueFormat};
+use crate::sysfs_sensor::{resolve_hwmon, resolve_thermal_zone, HWMON_ROOT, THERMAL_ROOT};
+use crate::temperature_recorder::{round, Temperature};
 
-use serde::{Deserialize, Serialize};
 use std::fs::read_to_string;
-use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
 
-pub struct TemperatureReader;
+pub struct TemperatureReader {
+    sensor_config: Arc<SensorConfig>,
+    cache: Arc<LastValueCache>,
+}
 
 #[derive(Debug)]
 pub enum TemperatureReaderError {
-    ConfigRead(std::io::Error),
-    ConfigParse(toml::de::Error),
-    SensorRead(std::io::Error, Sensor),
-    SensorParse(ParseIntError, Sensor, String),
-}
-
-#[derive(Deserialize, Serialize, Debug)]
-pub struct SensorConfig {
-    sensors: Vec<Sensor>,
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct Sensor {
-    name: String,
-    path: String,
+    SensorRead(std::io::Error, Box<Sensor>),
+    SensorParse(String, Box<Sensor>, String),
+    Command(CommandError, Box<Sensor>),
+    Http(HttpSensorError, Box<Sensor>),
+    Modbus(ModbusError, Box<Sensor>),
+    /// No value of a pushed sensor arrived within its cache duration
+    NoRecentValue(Box<Sensor>),
 }
 
 impl TemperatureReader {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(sensor_config: Arc<SensorConfig>, cache: Arc<LastValueCache>) -> Self {
+        Self {
+            sensor_config,
+            cache,
+        }
     }
 
-    pub fn read_config(config_file_path: &str) -> Result<SensorConfig, TemperatureReaderError> {
-        let sensor_file =
-            read_to_string(config_file_path).map_err(TemperatureReaderError::ConfigRead)?;
-        let sensor_config: SensorConfig =
-            toml::from_str(&sensor_file).map_err(TemperatureReaderError::ConfigParse)?;
+    pub fn read(&self) -> Result<Vec<Temperature>, TemperatureReaderError> {
+        // derived sensors are evaluated from the values read here
+        let sensors: Vec<&Sensor> = self
+            .sensor_config
+            .sensors()
+            .iter()
+            .filter(|sensor| !sensor.kind().is_derived())
+            .collect();
 
-        Ok(sensor_config)
-    }
+        // Sensors which may block are read in parallel, so the slowest of
+        // them bounds the time needed instead of their sum
+        let results: Vec<Result<f32, TemperatureReaderError>> = thread::scope(|scope| {
+            let handles: Vec<_> = sensors
+                .iter()
+                .map(|sensor| {
+                    if sensor.kind().may_block() {
+                        Some(scope.spawn(|| self.read_calibrated(sensor)))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
 
-    pub fn read(&self) -> Result<Vec<Temperature>, TemperatureReaderError> {
-        // TODO use app arguments
-        let sensor_config = Self::read_config("Sensor.toml")?;
+            sensors
+                .iter()
+                .zip(handles)
+                .map(|(sensor, handle)| match handle {
+                    Some(handle) => handle.join().unwrap_or_else(|_| {
+                        Err(TemperatureReaderError::SensorParse(
+                            String::from("Reading sensor panicked"),
+                            Box::new((*sensor).to_owned()),
+                            String::new(),
+                        ))
+                    }),
+                    None => self.read_calibrated(sensor),
+                })
+                .collect()
+        });
 
         let mut errors = vec![];
-        let temperatures: Vec<Temperature> = sensor_config
-            .sensors
+        let temperatures: Vec<Temperature> = sensors
             .iter()
-            .filter_map(|sensor| {
-                let temperature = Self::read_sensor(&sensor);
+            .zip(results)
+            .filter_map(|(sensor, temperature)| {
                 temperature
                     .map_err(|e| errors.push(e))
                     .ok()
-                    .map(|t| Temperature::new(sensor.name.clone(), t))
+                    .map(|t| Temperature::new(sensor.name().to_owned(), t))
             })
             .collect();
 
@@ -60,19 +89,231 @@ impl TemperatureReader {
             log::error!("Error reading sensors {:?}", errors);
         }
 
-        return Ok(temperatures);
+        Ok(temperatures)
+    }
+
+    /// Reads a single sensor and applies its calibration and precision.
+    pub fn read_calibrated(&self, sensor: &Sensor) -> Result<f32, TemperatureReaderError> {
+        let temperature = match sensor.kind() {
+            SensorKind::Http => self.read_remote_sensor(sensor)?,
+            SensorKind::Mqtt => self.read_pushed_sensor(sensor)?,
+            _ => Self::read_sensor(sensor)?,
+        } + sensor.offset();
+
+        Ok(match sensor.precision() {
+            Some(precision) => round(temperature, precision),
+            None => temperature,
+        })
+    }
+
+    /// Reads a remote sensor, falling back to its last value if it was read
+    /// successfully within its cache duration.
+    fn read_remote_sensor(&self, sensor: &Sensor) -> Result<f32, TemperatureReaderError> {
+        match Self::read_sensor(sensor) {
+            Ok(temperature) => {
+                self.cache.store(sensor.name(), temperature);
+                Ok(temperature)
+            }
+            Err(error) => match self.cache.recent(sensor.name(), sensor.cache_duration()) {
+                Some(temperature) => {
+                    log::warn!(
+                        "Using cached value of sensor {}: {:?}",
+                        sensor.name(),
+                        error
+                    );
+                    Ok(temperature)
+                }
+                None => Err(error),
+            },
+        }
+    }
+
+    /// Returns the last value received for a sensor pushing its values.
+    fn read_pushed_sensor(&self, sensor: &Sensor) -> Result<f32, TemperatureReaderError> {
+        self.cache
+            .recent(sensor.name(), sensor.cache_duration())
+            .ok_or_else(|| TemperatureReaderError::NoRecentValue(Box::new(sensor.to_owned())))
+    }
+
+    fn sensor_path(sensor: &Sensor) -> Result<PathBuf, TemperatureReaderError> {
+        let device = sensor.device().unwrap_or_default();
+        let path = match sensor.kind() {
+            SensorKind::Hwmon => resolve_hwmon(Path::new(HWMON_ROOT), device, sensor.label()),
+            SensorKind::Thermal => resolve_thermal_zone(Path::new(THERMAL_ROOT), device),
+            _ => Ok(PathBuf::from(sensor.path())),
+        };
+
+        path.map_err(|e| TemperatureReaderError::SensorRead(e, Box::new(sensor.to_owned())))
     }
 
     fn read_sensor(sensor: &Sensor) -> Result<f32, TemperatureReaderError> {
-        let sensor_file_content = read_to_string(&sensor.path)
-            .map_err(|e| TemperatureReaderError::SensorRead(e, sensor.to_owned()))?;
+        let content = match sensor.kind() {
+            SensorKind::Command => command_sensor::run(sensor.command(), sensor.timeout())
+                .map_err(|e| TemperatureReaderError::Command(e, Box::new(sensor.to_owned())))?,
+            SensorKind::Http => http_sensor::fetch(sensor.url(), sensor.timeout())
+                .map_err(|e| TemperatureReaderError::Http(e, Box::new(sensor.to_owned())))?,
+            SensorKind::Modbus => {
+                let value = modbus_sensor::read_value(
+                    sensor.address(),
+                    sensor.unit_id(),
+                    sensor.register(),
+                    sensor.data_type(),
+                    sensor.timeout(),
+                )
+                .map_err(|e| TemperatureReaderError::Modbus(e, Box::new(sensor.to_owned())))?;
+                return Ok(value * sensor.scale());
+            }
+            _ => read_to_string(Self::sensor_path(sensor)?)
+                .map_err(|e| TemperatureReaderError::SensorRead(e, Box::new(sensor.to_owned())))?,
+        };
+
+        let format = sensor.format().unwrap_or(sensor.kind().default_format());
+
+        parse_value(&content, format, sensor.json_pointer()).map_err(|e| {
+            TemperatureReaderError::SensorParse(e, Box::new(sensor.to_owned()), content)
+        })
+    }
+}
+
+/// Parses a temperature in degrees from the output of a sensor.
+///
+/// `nan` and `inf` are rejected, they parse as `f32` but can't be stored.
+pub fn parse_value(content: &str, format: ValueFormat, json_pointer: &str) -> Result<f32, String> {
+    let value = match format {
+        ValueFormat::Plain => content.trim().parse::<f32>().map_err(|e| e.to_string()),
+        ValueFormat::Millidegrees => content
+            .trim()
+            .parse::<i32>()
+            .map(|millidegrees| millidegrees as f32 / 1000.0)
+            .map_err(|e| e.to_string()),
+        ValueFormat::Json => {
+            let document: serde_json::Value =
+                serde_json::from_str(content).map_err(|e| e.to_string())?;
+            let value = document
+                .pointer(json_pointer)
+                .ok_or_else(|| format!("Nothing found at {}", json_pointer))?;
+
+            match value {
+                serde_json::Value::Number(number) => number.as_f64().map(|n| n as f32),
+                serde_json::Value::String(string) => string.trim().parse::<f32>().ok(),
+                _ => None,
+            }
+            .ok_or_else(|| format!("No number at {}: {}", json_pointer, value))
+        }
+    }?;
+
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(format!("Not a finite number: {}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_value_formats() {
+        assert_eq!(parse_value("21.5\n", ValueFormat::Plain, ""), Ok(21.5));
+        assert_eq!(
+            parse_value("21500\n", ValueFormat::Millidegrees, ""),
+            Ok(21.5)
+        );
+        assert_eq!(
+            parse_value(
+                r#"{"tank": {"top": 61.25}}"#,
+                ValueFormat::Json,
+                "/tank/top"
+            ),
+            Ok(61.25)
+        );
+        assert_eq!(
+            parse_value(
+                r#"{"temperature": "19.5"}"#,
+                ValueFormat::Json,
+                "/temperature"
+            ),
+            Ok(19.5)
+        );
+        assert!(parse_value(
+            r#"{"temperature": null}"#,
+            ValueFormat::Json,
+            "/temperature"
+        )
+        .is_err());
+        assert!(parse_value(
+            r#"{"temperature": "NaN"}"#,
+            ValueFormat::Json,
+            "/temperature"
+        )
+        .is_err());
+        assert!(parse_value("inf", ValueFormat::Plain, "").is_err());
+    }
+
+    #[test]
+    fn reads_command_sensor() {
+        let config = SensorConfig::parse(
+            r#"
+            [[sensors]]
+            name = "script"
+            kind = "command"
+            command = ["sh", "-c", "echo 48500"]
+            format = "millidegrees"
+        "#,
+        )
+        .unwrap();
+
+        let reader = TemperatureReader::new(Arc::new(config), Arc::default());
+        let temperature = reader
+            .read_calibrated(&reader.sensor_config.sensors()[0])
+            .unwrap();
+        assert_eq!(temperature, 48.5);
+    }
+
+    #[test]
+    fn rejects_nan_of_command_sensor() {
+        let config = SensorConfig::parse(
+            r#"
+            [[sensors]]
+            name = "script"
+            kind = "command"
+            command = ["echo", "nan"]
+        "#,
+        )
+        .unwrap();
+
+        let reader = TemperatureReader::new(Arc::new(config), Arc::default());
+        let result = reader.read_calibrated(&reader.sensor_config.sensors()[0]);
+        assert!(matches!(
+            result,
+            Err(TemperatureReaderError::SensorParse(..))
+        ));
+        assert!(reader.read().unwrap().is_empty());
+    }
 
-        let sensor_value = sensor_file_content.trim_end().parse::<i32>().map_err(|e| {
-            TemperatureReaderError::SensorParse(e, sensor.to_owned(), sensor_file_content)
-        })?;
+    #[test]
+    fn reads_http_sensor_and_falls_back_to_cached_value() {
+        let url = crate::http_sensor::tests::serve(r#"{"probe": {"celsius": 55.5}}"#, 1);
+        let config = SensorConfig::parse(&format!(
+            r#"
+            [[sensors]]
+            name = "esp32"
+            kind = "http"
+            url = "{}"
+            json_pointer = "/probe/celsius"
+            timeout_ms = 1000
+        "#,
+            url
+        ))
+        .unwrap();
 
-        let temperature = sensor_value as f32 / 1000.0;
+        let reader = TemperatureReader::new(Arc::new(config), Arc::default());
+        let temperatures = reader.read().unwrap();
+        assert_eq!(temperatures[0].value(), 55.5);
 
-        Ok(temperature)
+        // the stand-in only answers once, so this read falls back to the cache
+        let temperatures = reader.read().unwrap();
+        assert_eq!(temperatures[0].value(), 55.5);
     }
 }
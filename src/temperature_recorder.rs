@@ -1,49 +1,240 @@
+use crate::local_time::{iso_date, midnight};
+use crate::tank_energy::TankEnergy;
+
+use chrono::{Days, TimeZone};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, SystemTimeError, UNIX_EPOCH};
+
+pub const MILLIS_PER_DAY: u64 = 86_400_000;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RecorderConfig {
     pub interval_seconds: u32,
     pub keep_days: u64,
+    #[serde(default = "RecorderConfig::default_keep_days_5m")]
+    pub keep_days_5m: u64,
+    #[serde(default = "RecorderConfig::default_keep_days_1h")]
+    pub keep_days_1h: u64,
 }
 
 impl RecorderConfig {
-    pub fn new(interval_seconds: u32, keep_days: u64) -> Self {
+    pub fn new(
+        interval_seconds: u32,
+        keep_days: u64,
+        keep_days_5m: u64,
+        keep_days_1h: u64,
+    ) -> Self {
         Self {
             interval_seconds,
             keep_days,
+            keep_days_5m,
+            keep_days_1h,
+        }
+    }
+
+    pub fn default_keep_days_5m() -> u64 {
+        365
+    }
+
+    pub fn default_keep_days_1h() -> u64 {
+        3650
+    }
+
+    /// Rejects retention windows that shrink from a finer to a coarser tier,
+    /// which would delete rollups before the samples they summarise.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.keep_days_5m < self.keep_days {
+            return Err(format!(
+                "keep_days_5m ({}) must not be less than keep_days ({})",
+                self.keep_days_5m, self.keep_days
+            ));
+        }
+        if self.keep_days_1h < self.keep_days_5m {
+            return Err(format!(
+                "keep_days_1h ({}) must not be less than keep_days_5m ({})",
+                self.keep_days_1h, self.keep_days_5m
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn keep_days_for(&self, resolution: Resolution) -> u64 {
+        match resolution {
+            Resolution::Raw => self.keep_days,
+            Resolution::FiveMinutes => self.keep_days_5m,
+            Resolution::Hourly => self.keep_days_1h,
+        }
+    }
+
+    /// Start of the oldest local day to keep at `resolution`, so a day is
+    /// always kept or deleted as a whole.
+    pub fn retention_cutoff(&self, resolution: Resolution, now: u64, timezone: &Tz) -> u64 {
+        let today = match timezone.timestamp_millis_opt(now as i64).earliest() {
+            Some(now) => now.date_naive(),
+            None => return 0,
+        };
+
+        today
+            .checked_sub_days(Days::new(self.keep_days_for(resolution)))
+            .map(|day| midnight(day, timezone))
+            .unwrap_or_default()
+    }
+
+    /// Finest resolution whose retention window still reaches back to `since`,
+    /// falling back to the coarsest one for ranges older than every window.
+    pub fn resolution_since(&self, since: u64, now: u64) -> Resolution {
+        Resolution::ALL
+            .into_iter()
+            .find(|resolution| {
+                let keep_millis = self.keep_days_for(*resolution) * MILLIS_PER_DAY;
+                since >= now.saturating_sub(keep_millis)
+            })
+            .unwrap_or(Resolution::Hourly)
+    }
+}
+
+/// Storage tier of recorded temperatures: raw samples or min/avg/max rollups.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    Raw,
+    FiveMinutes,
+    Hourly,
+}
+
+impl Resolution {
+    pub const ALL: [Resolution; 3] = [Resolution::Raw, Resolution::FiveMinutes, Resolution::Hourly];
+
+    pub const ROLLUPS: [Resolution; 2] = [Resolution::FiveMinutes, Resolution::Hourly];
+
+    pub fn table(&self) -> &'static str {
+        match self {
+            Resolution::Raw => "temperatures",
+            Resolution::FiveMinutes => "temperatures_5m",
+            Resolution::Hourly => "temperatures_1h",
         }
     }
+
+    pub fn bucket_millis(&self) -> u64 {
+        match self {
+            Resolution::Raw => 1,
+            Resolution::FiveMinutes => 5 * 60 * 1000,
+            Resolution::Hourly => 60 * 60 * 1000,
+        }
+    }
+}
+
+pub fn now_millis() -> Result<u64, SystemTimeError> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
 }
 
 #[derive(Serialize, Debug, Clone)]
 pub struct Temperature {
     name: String,
-    value: f32,
+    /// `None` for markers of missing readings, serialized as `null`
+    value: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<f32>,
+    /// Reference to the configured sensor, if it still exists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sensor: Option<String>,
 }
 
 impl Temperature {
     pub fn new(name: String, value: f32) -> Self {
-        Self { name, value }
+        Self {
+            name,
+            value: Some(value),
+            min: None,
+            max: None,
+            sensor: None,
+        }
+    }
+
+    /// Marker of a reading missing in a gap of the recorded temperatures.
+    pub fn missing(name: String) -> Self {
+        Self {
+            name,
+            value: None,
+            min: None,
+            max: None,
+            sensor: None,
+        }
+    }
+
+    /// Aggregated temperature of a rollup bucket, `value` being the average.
+    pub fn with_range(name: String, value: f32, min: f32, max: f32) -> Self {
+        Self {
+            name,
+            value: Some(value),
+            min: Some(min),
+            max: Some(max),
+            sensor: None,
+        }
     }
 
     pub fn name(&self) -> String {
         self.name.to_owned()
     }
 
-    pub fn value_rounded_as_string(&self) -> String {
-        format!("{:.2}", self.value)
+    /// The value read, 0 for markers of missing readings.
+    pub fn value(&self) -> f32 {
+        self.value.unwrap_or_default()
+    }
+
+    pub fn is_missing(&self) -> bool {
+        self.value.is_none()
+    }
+
+    pub fn rounded(self, precision: u32) -> Self {
+        Self {
+            name: self.name,
+            value: self.value.map(|value| round(value, precision)),
+            min: self.min.map(|min| round(min, precision)),
+            max: self.max.map(|max| round(max, precision)),
+            sensor: self.sensor,
+        }
     }
+
+    pub fn with_sensor(self, sensor: Option<String>) -> Self {
+        Self { sensor, ..self }
+    }
+}
+
+/// Most decimal places worth keeping of an `f32` temperature.
+pub const MAX_PRECISION: u32 = 6;
+
+/// Rounds `value` to `precision` decimal places.
+pub fn round(value: f32, precision: u32) -> f32 {
+    let factor = 10f32.powi(precision as i32);
+    (value * factor).round() / factor
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct TemperaturesByTime {
     date: u64,
+    /// `date` in the configured timezone, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_iso: Option<String>,
     temperatures: Vec<Temperature>,
+    /// Energy stored in the hot water tank, if one is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tank: Option<TankEnergy>,
 }
 
 impl TemperaturesByTime {
     pub fn new(date: u64, temperatures: Vec<Temperature>) -> Self {
-        Self { date, temperatures }
+        Self {
+            date,
+            date_iso: None,
+            temperatures,
+            tank: None,
+        }
     }
 
     pub fn date(&self) -> u64 {
@@ -53,4 +244,70 @@ impl TemperaturesByTime {
     pub fn temperatures(&self) -> Vec<Temperature> {
         self.temperatures.clone()
     }
+
+    pub fn map_temperatures<F>(self, f: F) -> Self
+    where
+        F: FnMut(Temperature) -> Temperature,
+    {
+        Self {
+            date: self.date,
+            date_iso: self.date_iso,
+            temperatures: self.temperatures.into_iter().map(f).collect(),
+            tank: self.tank,
+        }
+    }
+
+    pub fn with_iso_date(self, timezone: &Tz) -> Self {
+        Self {
+            date_iso: Some(iso_date(self.date, timezone)),
+            ..self
+        }
+    }
+
+    pub fn with_tank(self, tank: Option<TankEnergy>) -> Self {
+        Self { tank, ..self }
+    }
+
+    pub fn rounded(self, precision: u32) -> Self {
+        let tank = self.tank.map(|tank| tank.rounded(precision));
+        self.map_temperatures(|temperature| temperature.rounded(precision))
+            .with_tank(tank)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolution_since_picks_finest_tier_reaching_back() {
+        let config = RecorderConfig::new(15, 30, 365, 3650);
+        let now = 4000 * MILLIS_PER_DAY;
+        let days_ago = |days: u64| now - days * MILLIS_PER_DAY;
+
+        assert_eq!(config.resolution_since(now, now), Resolution::Raw);
+        assert_eq!(config.resolution_since(days_ago(30), now), Resolution::Raw);
+        assert_eq!(
+            config.resolution_since(days_ago(31), now),
+            Resolution::FiveMinutes
+        );
+        assert_eq!(
+            config.resolution_since(days_ago(365), now),
+            Resolution::FiveMinutes
+        );
+        assert_eq!(
+            config.resolution_since(days_ago(366), now),
+            Resolution::Hourly
+        );
+        // older than every window, so the coarsest tier has the most left
+        assert_eq!(config.resolution_since(0, now), Resolution::Hourly);
+    }
+
+    #[test]
+    fn validate_rejects_shrinking_retention() {
+        assert!(RecorderConfig::new(15, 30, 365, 3650).validate().is_ok());
+        assert!(RecorderConfig::new(15, 30, 30, 30).validate().is_ok());
+        assert!(RecorderConfig::new(15, 30, 7, 3650).validate().is_err());
+        assert!(RecorderConfig::new(15, 30, 365, 90).validate().is_err());
+    }
 }